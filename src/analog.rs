@@ -0,0 +1,166 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    Analog model of the CGA composite output stage
+
+    The color generator selects one of eight phase-shifted copies of the color
+    clock for each pixel, and the intensity bit is mixed in through its own
+    resistor. Here each of those two outputs drives a first-order RC filter
+    with separate rise and fall time constants, and each color's clock can be
+    delayed by its own skew. The model runs at a high internal rate and is
+    box-filtered down to the output sample rate.
+*/
+
+use std::str::FromStr;
+use std::time::Instant;
+
+use crate::composite::{ModelParams, COLOR_GEN_HALF, HDOT_CLOCK};
+
+pub const OVERSAMPLE: u32 = 16; // Minimum internal samples per hdot
+
+/// Delay of each of the eight color clocks, in nanoseconds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ColorSkew(pub [f32; 8]);
+
+impl FromStr for ColorSkew {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f32>())
+            .collect::<Result<Vec<f32>, _>>()
+            .map_err(|e| format!("Bad value for color skew: {}", e))?;
+
+        let skew: [f32; 8] = values
+            .try_into()
+            .map_err(|_| "Bad value for color skew, expected 8 comma separated values".to_string())?;
+        Ok(ColorSkew(skew))
+    }
+}
+
+/// Time constants of the output stage, in nanoseconds.
+#[derive(Copy, Clone, Debug)]
+pub struct AnalogModel {
+    pub chroma_rise: f32,
+    pub chroma_fall: f32,
+    pub intensity_rise: f32,
+    pub intensity_fall: f32,
+    pub skew: ColorSkew,
+}
+
+impl Default for AnalogModel {
+    fn default() -> Self {
+        // A half hdot is about 35ns; these give edges similar to EDGE_RESPONSE without
+        // rounding off a whole half hdot.
+        Self {
+            chroma_rise: 8.0,
+            chroma_fall: 10.0,
+            intensity_rise: 10.0,
+            intensity_fall: 12.0,
+            skew: ColorSkew([0.0; 8]),
+        }
+    }
+}
+
+/// A first-order RC low-pass with separate time constants for rising and falling inputs.
+struct RcFilter {
+    rise_k: f32,
+    fall_k: f32,
+    value: f32,
+}
+
+impl RcFilter {
+    /// Create a filter for the given time constants in ns and a sample period in ns.
+    fn new(rise: f32, fall: f32, dt: f32) -> Self {
+        let k = |tau: f32| if tau > 0.0 { 1.0 - (-dt / tau).exp() } else { 1.0 };
+        Self {
+            rise_k: k(rise),
+            fall_k: k(fall),
+            value: 0.0,
+        }
+    }
+
+    #[inline]
+    fn step(&mut self, target: f32) -> f32 {
+        let k = if target > self.value { self.rise_k } else { self.fall_k };
+        self.value += (target - self.value) * k;
+        self.value
+    }
+}
+
+/// Convert a CGA index image into a composite signal of `samples_per_hdot` samples per pixel
+/// using the analog model. The output uses the same levels as `process_cga_composite`, and
+/// `edge_response` is ignored in favor of the RC time constants.
+pub fn process_cga_composite_analog(
+    cga_buf: &[u8],
+    img_out: &mut [f32],
+    img_w: u32,
+    img_h: u32,
+    samples_per_hdot: u32,
+    model: &AnalogModel,
+    params: &ModelParams,
+) {
+    let bench_t = Instant::now();
+
+    // Run internally at a whole multiple of the output rate.
+    let decimate = OVERSAMPLE.div_ceil(samples_per_hdot);
+    let oversample = samples_per_hdot * decimate;
+    let dt = 1.0e9 / (HDOT_CLOCK as f32 * oversample as f32);
+    let skew = model.skew.0.map(|ns| (ns / dt).round() as i32);
+
+    for y in 0..img_h {
+        let line = &cga_buf[(y * img_w) as usize..((y + 1) * img_w) as usize];
+        let out_line = &mut img_out[(y * img_w * samples_per_hdot) as usize..((y + 1) * img_w * samples_per_hdot) as usize];
+
+        let mut chroma = RcFilter::new(model.chroma_rise, model.chroma_fall, dt);
+        let mut intensity = RcFilter::new(model.intensity_rise, model.intensity_fall, dt);
+        let mut acc = 0.0;
+
+        for k in 0..(img_w * oversample) as i32 {
+            let color = line[(k / oversample as i32) as usize];
+            let base_color = (color % 8) as usize;
+
+            // The mux switches on pixel boundaries; the selected color clock lags by its skew.
+            let t = k - skew[base_color];
+            let half_hdot = (t * 2).div_euclid(oversample as i32).rem_euclid(8) as usize;
+
+            let c = chroma.step(COLOR_GEN_HALF[base_color][half_hdot]);
+            let i = intensity.step(if color > 7 { 1.0 } else { 0.0 });
+            acc += c * params.luma_attenuate + i * params.intensity_gain;
+
+            if (k as u32 + 1).is_multiple_of(decimate) {
+                let o = k as u32 / decimate;
+                if let Some(out) = out_line.get_mut(o as usize) {
+                    *out = acc / decimate as f32;
+                }
+                acc = 0.0;
+            }
+        }
+    }
+
+    let us = (Instant::now() - bench_t).as_micros();
+    log::debug!("Analog composite conversion took: {} milliseconds", us as f32 / 1000.0);
+}
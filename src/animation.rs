@@ -0,0 +1,460 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    Animated input and output
+
+    Animated GIFs and APNGs are composited frame by frame into full RGBA
+    images, following each frame's disposal and blending. Numbered frame
+    dumps are read from a directory or a printf style pattern and shown at
+    the CGA's refresh rate unless told otherwise. Decoded frames are written
+    as an APNG, an animated GIF or a numbered image sequence, keeping the
+    delay of each input frame.
+*/
+
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::composite::{HDOT_CLOCK, LINE_HDOTS};
+use crate::output::{save_output, FloatImage, OutputFormat};
+use crate::waveform::FRAME_LINES;
+
+/// Frames per second of the CGA's 262 line progressive output.
+pub const CGA_FRAME_RATE: f64 = HDOT_CLOCK / (LINE_HDOTS * FRAME_LINES) as f64;
+
+const SEQUENCE_EXTENSIONS: [&str; 4] = ["png", "gif", "bmp", "pcx"];
+const GIF_QUANTIZE_SPEED: i32 = 10; // NeuQuant speed, 1 (best) to 30 (fastest)
+
+/// Container for decoded animations.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AnimationFormat {
+    Apng,
+    Gif,
+    /// Numbered images in the --format file format.
+    Sequence,
+}
+
+impl FromStr for AnimationFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "apng" => Ok(AnimationFormat::Apng),
+            "gif" => Ok(AnimationFormat::Gif),
+            "sequence" | "seq" => Ok(AnimationFormat::Sequence),
+            _ => Err("Bad value for animation format, expected apng, gif or sequence".to_string()),
+        }
+    }
+}
+
+/// One composited frame of an animated image, and how long it is shown in seconds.
+pub struct RgbaFrame {
+    pub rgba: Vec<u8>,
+    pub delay: f64,
+}
+
+/// The frames of an animated image, all of the same size.
+pub struct Animation {
+    pub width: u32,
+    pub height: u32,
+    pub frames: Vec<RgbaFrame>,
+}
+
+/// Load an animated GIF or APNG. Returns None for files that aren't animated, or have a single
+/// frame, which should be loaded as still images.
+pub fn load_animation(path: &Path) -> Result<Option<Animation>, String> {
+    let data = std::fs::read(path).map_err(|e| format!("Couldn't open input image '{:?}': {}", path, e))?;
+
+    let animation = if data.starts_with(b"GIF8") {
+        load_gif(&data)?
+    } else if data.starts_with(b"\x89PNG") {
+        load_apng(&data)?
+    } else {
+        None
+    };
+    Ok(animation.filter(|a| a.frames.len() > 1))
+}
+
+/// Return the frames of an image sequence: the images in a directory in name order, with
+/// numbers compared by value, or the files matching a pattern with a printf style %d, %Nd or
+/// %0Nd counter, starting from 0 or 1. Returns None if `path` is neither.
+pub fn sequence_paths(path: &Path) -> Result<Option<Vec<PathBuf>>, String> {
+    if path.is_dir() {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(path)
+            .map_err(|e| format!("Couldn't read directory '{:?}': {}", path, e))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| {
+                p.extension()
+                    .and_then(|e| e.to_str())
+                    .map(|e| SEQUENCE_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+                    .unwrap_or(false)
+            })
+            .collect();
+        paths.sort_by(|a, b| natural_cmp(&a.to_string_lossy(), &b.to_string_lossy()));
+        if paths.is_empty() {
+            return Err(format!("No images found in directory '{:?}'", path));
+        }
+        return Ok(Some(paths));
+    }
+
+    if path.is_file() {
+        return Ok(None);
+    }
+    let pattern = path.to_string_lossy();
+    let Some(counter) = find_counter(&pattern) else {
+        return Ok(None);
+    };
+
+    let (prefix, suffix) = (&pattern[..counter.start], &pattern[counter.end..]);
+    let name = |n: usize| {
+        let number = if counter.zero_pad {
+            format!("{:0width$}", n, width = counter.width)
+        } else {
+            format!("{:width$}", n, width = counter.width)
+        };
+        PathBuf::from(format!("{}{}{}", prefix, number, suffix))
+    };
+    let first = (0..=1).find(|&n| name(n).is_file());
+    let paths: Vec<PathBuf> = match first {
+        Some(first) => (first..).map(name).take_while(|p| p.is_file()).collect(),
+        None => return Err(format!("No images match the sequence pattern '{}'", pattern)),
+    };
+    Ok(Some(paths))
+}
+
+/// A printf style counter in a sequence pattern.
+struct Counter {
+    start: usize,
+    end: usize,
+    width: usize,
+    zero_pad: bool,
+}
+
+/// Find the first %d, %Nd or %0Nd in a pattern. Other uses of % are left alone.
+fn find_counter(pattern: &str) -> Option<Counter> {
+    pattern.match_indices('%').find_map(|(start, _)| {
+        let spec = &pattern[start + 1..];
+        let digits = spec.bytes().take_while(u8::is_ascii_digit).count();
+        if !spec[digits..].starts_with('d') {
+            return None;
+        }
+        Some(Counter {
+            start,
+            end: start + digits + 2,
+            width: if digits == 0 { 0 } else { spec[..digits].parse().ok()? },
+            zero_pad: spec.starts_with('0'),
+        })
+    })
+}
+
+/// Compare names with runs of digits ordered by value, so frame_2 comes before frame_10.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    loop {
+        let (da, db) = (
+            a.iter().take_while(|c| c.is_ascii_digit()).count(),
+            b.iter().take_while(|c| c.is_ascii_digit()).count(),
+        );
+        let order = if da > 0 && db > 0 {
+            // Compare digit runs by value: strip leading zeros, then the longer run is larger.
+            let (na, nb) = (strip_zeros(&a[..da]), strip_zeros(&b[..db]));
+            let order = na.len().cmp(&nb.len()).then_with(|| na.cmp(nb));
+            (a, b) = (&a[da..], &b[db..]);
+            order
+        } else {
+            match (a.split_first(), b.split_first()) {
+                (Some((ca, ra)), Some((cb, rb))) => {
+                    (a, b) = (ra, rb);
+                    ca.cmp(cb)
+                }
+                (x, y) => return x.is_some().cmp(&y.is_some()),
+            }
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+}
+
+fn strip_zeros(digits: &[u8]) -> &[u8] {
+    let first = digits.iter().position(|&c| c != b'0').unwrap_or(digits.len());
+    &digits[first..]
+}
+
+/// Read every frame of a GIF, as RGBA.
+fn load_gif(data: &[u8]) -> Result<Option<Animation>, String> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(data).map_err(|e| format!("Error reading GIF: {}", e))?;
+
+    let (width, height) = (decoder.width() as usize, decoder.height() as usize);
+    let mut canvas = vec![0u8; width * height * 4];
+    let mut frames = Vec::new();
+
+    while let Some(frame) = decoder.read_next_frame().map_err(|e| format!("Error reading GIF: {}", e))? {
+        let rect = Rect {
+            x: frame.left as usize,
+            y: frame.top as usize,
+            w: frame.width as usize,
+            h: frame.height as usize,
+        };
+        let previous = canvas.clone();
+
+        // Transparent pixels leave the canvas showing through.
+        blit(&mut canvas, width, height, &frame.buffer, &rect, true);
+        frames.push(RgbaFrame {
+            rgba: canvas.clone(),
+            delay: frame.delay as f64 / 100.0,
+        });
+
+        match frame.dispose {
+            gif::DisposalMethod::Background => clear(&mut canvas, width, height, &rect),
+            gif::DisposalMethod::Previous => canvas = previous,
+            _ => {}
+        }
+    }
+
+    Ok(Some(Animation {
+        width: width as u32,
+        height: height as u32,
+        frames,
+    }))
+}
+
+/// Read every frame of an APNG, as RGBA. Returns None for PNGs without animation.
+fn load_apng(data: &[u8]) -> Result<Option<Animation>, String> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(|e| format!("Error reading PNG: {}", e))?;
+
+    let num_frames = match reader.info().animation_control {
+        Some(actl) if actl.num_frames > 1 => actl.num_frames,
+        _ => return Ok(None),
+    };
+    let (width, height) = (reader.info().width as usize, reader.info().height as usize);
+    let (color_type, _) = reader.output_color_type();
+
+    let mut buf = vec![0; reader.output_buffer_size()];
+
+    // The default image is only shown by viewers without APNG support unless it has a frame
+    // control chunk of its own.
+    if reader.info().frame_control.is_none() {
+        reader.next_frame(&mut buf).map_err(|e| format!("Error reading PNG: {}", e))?;
+    }
+
+    let mut canvas = vec![0u8; width * height * 4];
+    let mut frames = Vec::new();
+
+    for _ in 0..num_frames {
+        let out = reader.next_frame(&mut buf).map_err(|e| format!("Error reading PNG: {}", e))?;
+        let fctl = reader.info().frame_control.ok_or("APNG frame has no frame control")?;
+        let rect = Rect {
+            x: fctl.x_offset as usize,
+            y: fctl.y_offset as usize,
+            w: fctl.width as usize,
+            h: fctl.height as usize,
+        };
+
+        let rgba = to_rgba(&buf[..out.line_size * rect.h], out.line_size, rect.w, color_type)?;
+        let previous = canvas.clone();
+        blit(&mut canvas, width, height, &rgba, &rect, fctl.blend_op == png::BlendOp::Over);
+
+        // A zero denominator means hundredths of a second.
+        let den = if fctl.delay_den == 0 { 100 } else { fctl.delay_den };
+        frames.push(RgbaFrame {
+            rgba: canvas.clone(),
+            delay: fctl.delay_num as f64 / den as f64,
+        });
+
+        match fctl.dispose_op {
+            png::DisposeOp::Background => clear(&mut canvas, width, height, &rect),
+            png::DisposeOp::Previous => canvas = previous,
+            png::DisposeOp::None => {}
+        }
+    }
+
+    Ok(Some(Animation {
+        width: width as u32,
+        height: height as u32,
+        frames,
+    }))
+}
+
+/// Convert rows of 8 bit PNG output to RGBA.
+fn to_rgba(data: &[u8], line_size: usize, width: usize, color_type: png::ColorType) -> Result<Vec<u8>, String> {
+    let channels = match color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::Rgb => 3,
+        png::ColorType::Rgba => 4,
+        png::ColorType::Indexed => return Err("Unexpanded indexed PNG frame".to_string()),
+    };
+
+    let mut rgba = Vec::with_capacity(data.len() / line_size * width * 4);
+    for row in data.chunks(line_size) {
+        for px in row[..width * channels].chunks(channels) {
+            rgba.extend_from_slice(&match channels {
+                1 => [px[0], px[0], px[0], 0xFF],
+                2 => [px[0], px[0], px[0], px[1]],
+                3 => [px[0], px[1], px[2], 0xFF],
+                _ => [px[0], px[1], px[2], px[3]],
+            });
+        }
+    }
+    Ok(rgba)
+}
+
+/// A frame's region of the canvas.
+struct Rect {
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+}
+
+/// Draw RGBA pixels into a region of the canvas, skipping transparent pixels if `over` is set.
+fn blit(canvas: &mut [u8], width: usize, height: usize, rgba: &[u8], rect: &Rect, over: bool) {
+    for (fy, row) in rgba.chunks(rect.w * 4).take(rect.h).enumerate() {
+        for (fx, px) in row.chunks(4).enumerate() {
+            let (x, y) = (rect.x + fx, rect.y + fy);
+            if x < width && y < height && !(over && px[3] < 0x80) {
+                let o = (y * width + x) * 4;
+                canvas[o..o + 4].copy_from_slice(px);
+            }
+        }
+    }
+}
+
+/// Clear a region of the canvas to transparent black.
+fn clear(canvas: &mut [u8], width: usize, height: usize, rect: &Rect) {
+    for y in rect.y..(rect.y + rect.h).min(height) {
+        for x in rect.x..(rect.x + rect.w).min(width) {
+            let o = (y * width + x) * 4;
+            canvas[o..o + 4].fill(0);
+        }
+    }
+}
+
+/// Return an APNG frame delay as a fraction of a second with a 16 bit numerator.
+fn apng_delay(seconds: f64) -> (u16, u16) {
+    [10000u16, 1000, 100, 1]
+        .iter()
+        .map(|&den| ((seconds * den as f64).round(), den))
+        .find(|(num, _)| *num <= u16::MAX as f64)
+        .map(|(num, den)| (num as u16, den))
+        .unwrap_or((u16::MAX, 1))
+}
+
+/// Save decoded frames as `<stem>.png` or `<stem>.gif`, or as numbered `<stem>_NNNN` images,
+/// and report the result on the console. `delays` are in seconds.
+pub fn save_animation(frames: &[FloatImage], delays: &[f64], stem: &str, animation: AnimationFormat, format: OutputFormat) {
+    let result = match animation {
+        AnimationFormat::Apng => save_apng(frames, delays, stem, format == OutputFormat::Png16),
+        AnimationFormat::Gif => save_gif(frames, delays, stem),
+        AnimationFormat::Sequence => {
+            for (i, frame) in frames.iter().enumerate() {
+                save_output(frame, &format!("{}_{:04}", stem, i), format);
+            }
+            return;
+        }
+    };
+
+    match result {
+        Ok(filename) => println!("Wrote {} ({} frames)!", filename.trim_start_matches("./"), frames.len()),
+        Err(e) => {
+            println!("Error writing output file: {}", e)
+        }
+    }
+}
+
+fn save_apng(frames: &[FloatImage], delays: &[f64], stem: &str, sixteen_bit: bool) -> Result<String, String> {
+    let filename = format!("{}.png", stem);
+    let file = File::create(&filename).map_err(|e| e.to_string())?;
+
+    let mut encoder = png::Encoder::new(BufWriter::new(file), frames[0].width, frames[0].height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(if sixteen_bit { png::BitDepth::Sixteen } else { png::BitDepth::Eight });
+    encoder.set_animated(frames.len() as u32, 0).map_err(|e| e.to_string())?;
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+
+    for (frame, delay) in frames.iter().zip(delays) {
+        let (num, den) = apng_delay(*delay);
+        writer.set_frame_delay(num, den).map_err(|e| e.to_string())?;
+        let data = if sixteen_bit {
+            frame.to_rgba16().iter().flat_map(|v| v.to_be_bytes()).collect()
+        } else {
+            frame.to_rgba8()
+        };
+        writer.write_image_data(&data).map_err(|e| e.to_string())?;
+    }
+    writer.finish().map_err(|e| e.to_string())?;
+    Ok(filename)
+}
+
+fn save_gif(frames: &[FloatImage], delays: &[f64], stem: &str) -> Result<String, String> {
+    let filename = format!("{}.gif", stem);
+    let (width, height) = (frames[0].width, frames[0].height);
+    if width > u16::MAX as u32 || height > u16::MAX as u32 {
+        return Err(format!("{}x{} is too large for a GIF", width, height));
+    }
+    let file = File::create(&filename).map_err(|e| e.to_string())?;
+
+    let mut encoder =
+        gif::Encoder::new(BufWriter::new(file), width as u16, height as u16, &[]).map_err(|e| e.to_string())?;
+    encoder.set_repeat(gif::Repeat::Infinite).map_err(|e| e.to_string())?;
+
+    for (frame, delay) in frames.iter().zip(delays) {
+        let mut rgba = frame.to_rgba8();
+        let mut gif_frame = gif::Frame::from_rgba_speed(width as u16, height as u16, &mut rgba, GIF_QUANTIZE_SPEED);
+        gif_frame.delay = (delay * 100.0).round().min(u16::MAX as f64) as u16;
+        encoder.write_frame(&gif_frame).map_err(|e| e.to_string())?;
+    }
+    Ok(filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counter_follows_percent() {
+        let counter = find_counter("dumps%x/frame_%04d.png").unwrap();
+        assert_eq!((counter.start, counter.end), (14, 18));
+        assert_eq!((counter.width, counter.zero_pad), (4, true));
+
+        let counter = find_counter("frame_%5d.png").unwrap();
+        assert_eq!((counter.width, counter.zero_pad), (5, false));
+        assert!(find_counter("frame_%s.png").is_none());
+    }
+
+    #[test]
+    fn names_sort_by_number() {
+        let mut names = vec!["frame_10.png", "frame_2.png", "frame_1.png", "frame_02b.png"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, ["frame_1.png", "frame_2.png", "frame_02b.png", "frame_10.png"]);
+    }
+}
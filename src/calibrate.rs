@@ -0,0 +1,362 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    Model calibration
+
+    Fits the composite model's levels to captures of real hardware. Each
+    source image is encoded and decoded with the current parameters, and the
+    difference from its capture is minimized with Levenberg-Marquardt using a
+    numeric Jacobian.
+*/
+
+use std::time::Instant;
+
+use image::imageops::FilterType;
+
+use crate::colorimetry::{Colorimetry, DecodeMatrix, Primaries};
+use crate::composite::{CompositeSignal, EncoderModel, ModelParams};
+use crate::ntsc::{self, CombFilter, DecodeSettings, FilterBandwidth, OutputType};
+use crate::output::FloatImage;
+use crate::profile::Profile;
+use crate::rgbi::MatchOptions;
+use crate::vram::{self, Register};
+use crate::{load_cga_image, reenigne_context, CalibrateArgs, SampleMethod};
+
+const MIN_IMPROVEMENT: f64 = 1.0e-6; // Stop once the cost improves by less than this fraction
+const JACOBIAN_STEP: f64 = 1.0e-2; // Relative step for numeric derivatives
+const MAX_LAMBDA: f64 = 1.0e10;
+
+/// A source image and its capture, resampled to the decoder's output size.
+struct CalibrationPair {
+    cga_buf: Vec<u8>,
+    width: u32,
+    height: u32,
+    /// Mode register value for the reenigne decoder.
+    mode: u8,
+    capture: Vec<[f32; 3]>,
+}
+
+/// Run the calibrate subcommand.
+pub fn run(args: &CalibrateArgs) -> Result<(), String> {
+    if args.source.is_empty() || args.source.len() != args.capture.len() {
+        return Err("Expected one --capture for each --source".to_string());
+    }
+
+    let mut pairs = Vec::new();
+    for (source, capture) in args.source.iter().zip(&args.capture) {
+        let (cga_buf, width, height, registers) = load_cga_image(source, &MatchOptions::default())?;
+        let mode = args
+            .mode_reg
+            .map(|Register(value)| value)
+            .or(registers.map(|regs| regs.mode))
+            .unwrap_or(vram::MODE_TEXT_80);
+        let capture_img = image::open(capture)
+            .map_err(|e| format!("Couldn't open capture '{:?}': {}", capture, e))?
+            .resize_exact(width, height, FilterType::Triangle)
+            .to_rgb32f();
+
+        pairs.push(CalibrationPair {
+            cga_buf,
+            width,
+            height,
+            mode,
+            capture: capture_img.pixels().map(|p| p.0).collect(),
+        });
+    }
+
+    // Decode through the profile as the decode command would, with command line options
+    // taking precedence.
+    let profile = match &args.profile {
+        Some(name) => Profile::load(name).map_err(|e| format!("Couldn't load profile '{}': {}", name, e))?,
+        None => Profile::default(),
+    };
+    let monitor = &profile.monitor;
+
+    let start = match &args.model {
+        Some(path) => ModelParams::load(path)?,
+        None => profile.card.model,
+    };
+
+    let matrix = monitor.matrix.unwrap_or(DecodeMatrix::FccYiq);
+    let tint = args.tint.or(monitor.tint).unwrap_or(0.0);
+    let settings = DecodeSettings {
+        hue: args.hue.or(monitor.hue).unwrap_or(ntsc::HUE) + tint.to_radians(),
+        sat: args.sat.or(monitor.sat).unwrap_or(ntsc::SAT),
+        luma: args.luma.or(monitor.luma).unwrap_or(ntsc::BRI),
+        bandwidth: FilterBandwidth {
+            luma: monitor.luma_bw.unwrap_or(ntsc::LUMA_BW),
+            i: monitor.i_bw.unwrap_or(ntsc::I_BW),
+            q: monitor.q_bw.unwrap_or(ntsc::Q_BW),
+        },
+        comb: monitor.comb.unwrap_or(CombFilter::Notch),
+        colorimetry: Colorimetry::new(matrix, monitor.primaries.unwrap_or(Primaries::None), false),
+    };
+    let method = args.method;
+    let stride = args.stride.max(1);
+
+    // Check the profile's multiplexer table once, rather than on every evaluation.
+    reenigne_context(&profile, matrix, tint, start.intensity)?;
+    let decoder = Decoder {
+        profile: &profile,
+        matrix,
+        tint,
+        method,
+        settings: &settings,
+    };
+
+    let residuals = |p: &[f64]| -> Vec<f64> {
+        let params = unpack(&start, method, p);
+        let mut r = Vec::new();
+        for pair in &pairs {
+            let decoded = decoder.decode(pair, &params);
+            for y in (0..pair.height).step_by(stride as usize) {
+                for x in (0..pair.width).step_by(stride as usize) {
+                    let o = (y * pair.width + x) as usize;
+                    for c in 0..3 {
+                        // Captures are clamped, so compare against the clamped decode.
+                        let d = decoded.pixels[o][c].clamp(0.0, 1.0);
+                        r.push((d - pair.capture[o][c]) as f64);
+                    }
+                }
+            }
+        }
+        r
+    };
+
+    let bench_t = Instant::now();
+    let p0 = pack(&start, method);
+    let rms_before = rms(&residuals(&p0));
+    let p = levenberg_marquardt(&p0, residuals, args.iterations);
+    let fitted = unpack(&start, method, &p);
+    let rms_after = rms(&residuals(&p));
+    log::debug!("Calibration took: {} ms", (Instant::now() - bench_t).as_millis());
+
+    println!("RMS error: {:.5} -> {:.5}", rms_before, rms_after);
+    println!("{:#?}", fitted);
+
+    fitted.save(&args.output)?;
+    println!("Wrote {}!", args.output.display());
+    Ok(())
+}
+
+/// Return the parameters fitted for the given method.
+fn pack(params: &ModelParams, method: SampleMethod) -> Vec<f64> {
+    match method {
+        SampleMethod::Reenigne | SampleMethod::Mono => params.intensity.to_vec(),
+        _ => vec![
+            params.luma_attenuate as f64,
+            params.intensity_gain as f64,
+            params.edge_response as f64,
+        ],
+    }
+}
+
+/// Return a copy of `base` with the fitted parameters replaced.
+fn unpack(base: &ModelParams, method: SampleMethod, p: &[f64]) -> ModelParams {
+    let mut params = *base;
+    match method {
+        SampleMethod::Reenigne | SampleMethod::Mono => params.intensity.copy_from_slice(p),
+        _ => {
+            params.luma_attenuate = p[0] as f32;
+            params.intensity_gain = p[1] as f32;
+            params.edge_response = p[2] as f32;
+        }
+    }
+    params
+}
+
+/// The decoder setup a calibration fits through.
+struct Decoder<'a> {
+    profile: &'a Profile,
+    matrix: DecodeMatrix,
+    tint: f32,
+    method: SampleMethod,
+    settings: &'a DecodeSettings,
+}
+
+impl Decoder<'_> {
+    /// Encode and decode a source image with the given parameters.
+    fn decode(&self, pair: &CalibrationPair, params: &ModelParams) -> FloatImage {
+        match self.method {
+            SampleMethod::Reenigne | SampleMethod::Mono => {
+                // The profile's multiplexer table was checked before fitting.
+                let mut ctx = reenigne_context(self.profile, self.matrix, self.tint, params.intensity).unwrap();
+                ctx.set_monochrome(matches!(self.method, SampleMethod::Mono));
+                ctx.update_cga16_color(pair.mode);
+                let mut rgb_out = ctx.decode_image(&pair.cga_buf, pair.width, pair.height);
+                rgb_out.map_in_place(|rgb| self.settings.colorimetry.apply(rgb));
+                rgb_out
+            }
+            _ => {
+                let signal =
+                    CompositeSignal::encode(&pair.cga_buf, pair.width, pair.height, 2, &EncoderModel::Digital, params);
+                let mut rgb_out = FloatImage::new(pair.width, pair.height);
+                ntsc::process(&signal, &mut rgb_out, self.method, self.settings, OutputType::Rgb);
+                rgb_out
+            }
+        }
+    }
+}
+
+fn rms(r: &[f64]) -> f64 {
+    (r.iter().map(|v| v * v).sum::<f64>() / r.len().max(1) as f64).sqrt()
+}
+
+/// Minimize the sum of squared residuals with Levenberg-Marquardt, starting from `p0`.
+pub fn levenberg_marquardt(p0: &[f64], residuals: impl Fn(&[f64]) -> Vec<f64>, iterations: u32) -> Vec<f64> {
+    let n = p0.len();
+    let mut p = p0.to_vec();
+    let mut r = residuals(&p);
+    let mut cost: f64 = r.iter().map(|v| v * v).sum();
+    let mut lambda = 1.0e-3;
+
+    for iteration in 0..iterations {
+        // Forward difference Jacobian, one column per parameter.
+        let jacobian: Vec<Vec<f64>> = (0..n)
+            .map(|j| {
+                let h = JACOBIAN_STEP * p[j].abs().max(JACOBIAN_STEP);
+                let mut pj = p.clone();
+                pj[j] += h;
+                residuals(&pj).iter().zip(&r).map(|(a, b)| (a - b) / h).collect()
+            })
+            .collect();
+
+        // Normal equations: (JtJ + lambda * diag(JtJ)) delta = -Jt r
+        let mut jtj = vec![vec![0.0; n]; n];
+        let mut jtr = vec![0.0; n];
+        for a in 0..n {
+            for b in 0..n {
+                jtj[a][b] = jacobian[a].iter().zip(&jacobian[b]).map(|(x, y)| x * y).sum();
+            }
+            jtr[a] = jacobian[a].iter().zip(&r).map(|(x, y)| x * y).sum();
+        }
+
+        let mut improved = false;
+        while lambda < MAX_LAMBDA {
+            let mut m = jtj.clone();
+            for (d, row) in m.iter_mut().enumerate() {
+                row[d] += lambda * jtj[d][d].max(1.0e-12);
+            }
+            let rhs: Vec<f64> = jtr.iter().map(|v| -v).collect();
+
+            if let Some(delta) = solve_linear(m, rhs) {
+                let trial: Vec<f64> = p.iter().zip(&delta).map(|(a, d)| a + d).collect();
+                let trial_r = residuals(&trial);
+                let trial_cost: f64 = trial_r.iter().map(|v| v * v).sum();
+
+                if trial_cost < cost {
+                    let gain = (cost - trial_cost) / cost;
+                    p = trial;
+                    r = trial_r;
+                    cost = trial_cost;
+                    lambda = (lambda / 10.0).max(1.0e-12);
+                    improved = gain > MIN_IMPROVEMENT;
+                    break;
+                }
+            }
+            lambda *= 10.0;
+        }
+
+        println!("Iteration {}: RMS error {:.5}", iteration + 1, (cost / r.len().max(1) as f64).sqrt());
+        if !improved {
+            break;
+        }
+    }
+    p
+}
+
+/// Solve a small dense linear system with Gaussian elimination and partial pivoting.
+fn solve_linear(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1.0e-15 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        let (top, bottom) = a.split_at_mut(col + 1);
+        let pivot_row = &top[col];
+        for (offset, row) in bottom.iter_mut().enumerate() {
+            let f = row[col] / pivot_row[col];
+            for (v, p) in row[col..].iter_mut().zip(&pivot_row[col..]) {
+                *v -= f * p;
+            }
+            b[col + 1 + offset] -= f * b[col];
+        }
+    }
+
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = ((row + 1)..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+    Some(x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: &[f64], b: &[f64], tolerance: f64) {
+        for (x, y) in a.iter().zip(b) {
+            assert!((x - y).abs() < tolerance, "{:?} != {:?}", a, b);
+        }
+    }
+
+    #[test]
+    fn fits_linear_model() {
+        let xs: Vec<f64> = (0..10).map(|x| x as f64).collect();
+        let residuals = |p: &[f64]| xs.iter().map(|x| p[0] * x + p[1] - (2.0 * x - 1.0)).collect();
+        let p = levenberg_marquardt(&[0.0, 0.0], residuals, 50);
+        assert_close(&p, &[2.0, -1.0], 1.0e-4);
+    }
+
+    #[test]
+    fn fits_exponential_model() {
+        let xs: Vec<f64> = (0..20).map(|x| x as f64 * 0.25).collect();
+        let residuals = |p: &[f64]| {
+            xs.iter()
+                .map(|x| p[0] * (p[1] * x).exp() - 3.0 * (-0.5 * x).exp())
+                .collect()
+        };
+        let p = levenberg_marquardt(&[1.0, -0.1], residuals, 100);
+        assert_close(&p, &[3.0, -0.5], 1.0e-3);
+    }
+
+    #[test]
+    fn solves_with_pivoting() {
+        // The first pivot is zero, so rows must be swapped.
+        let x = solve_linear(vec![vec![0.0, 2.0], vec![3.0, 1.0]], vec![4.0, 5.0]).unwrap();
+        assert_close(&x, &[1.0, 2.0], 1.0e-12);
+    }
+
+    #[test]
+    fn singular_system_has_no_solution() {
+        assert!(solve_linear(vec![vec![1.0, 2.0], vec![2.0, 4.0]], vec![1.0, 2.0]).is_none());
+    }
+}
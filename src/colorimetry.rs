@@ -0,0 +1,301 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    Colorimetry routines
+
+    Decoding matrices, display primaries and transfer functions used to turn
+    demodulated YIQ into sRGB output.
+*/
+
+use std::str::FromStr;
+
+use cgmath::{Matrix3, SquareMatrix, Vector3};
+
+/// Gamma assumed for the CRT the NTSC signal was designed to drive.
+const CRT_GAMMA: f32 = 2.2;
+
+// CIE xy chromaticities of the primaries and white points we know about.
+const NTSC_1953_PRIMARIES: [(f64, f64); 3] = [(0.67, 0.33), (0.21, 0.71), (0.14, 0.08)];
+const SMPTE_C_PRIMARIES: [(f64, f64); 3] = [(0.630, 0.340), (0.310, 0.595), (0.155, 0.070)];
+const SRGB_PRIMARIES: [(f64, f64); 3] = [(0.64, 0.33), (0.30, 0.60), (0.15, 0.06)];
+
+// Chromaticities of common monochrome monitor phosphors.
+const P31_GREEN: (f64, f64) = (0.226, 0.529);
+const AMBER: (f64, f64) = (0.523, 0.469);
+
+const WHITE_C: (f64, f64) = (0.3101, 0.3162);
+const WHITE_D65: (f64, f64) = (0.3127, 0.3290);
+
+#[rustfmt::skip]
+const BRADFORD: [[f64; 3]; 3] = [
+    [ 0.8951,  0.2664, -0.1614],
+    [-0.7502,  1.7135,  0.0367],
+    [ 0.0389, -0.0685,  1.0296],
+];
+
+/// The matrix used to turn demodulated chroma back into RGB.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DecodeMatrix {
+    /// The FCC YIQ matrix, demodulating on the I and Q axes. The SMPTE-C YUV matrix is
+    /// accepted as an alias: U and V are I and Q rotated by 33 degrees, so demodulating on the
+    /// U and V axes and applying the YUV matrix gives the same RGB.
+    FccYiq,
+}
+
+impl FromStr for DecodeMatrix {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "fcc" | "yiq" | "fcc-yiq" | "smpte-c" | "yuv" | "smpte-c-yuv" => Ok(DecodeMatrix::FccYiq),
+            _ => Err("Bad value for matrix, expected fcc-yiq or smpte-c-yuv".to_string()),
+        }
+    }
+}
+
+impl DecodeMatrix {
+    /// Return the (I, Q) coefficients for each of the R, G and B outputs.
+    pub fn iq_coefficients(&self) -> [[f64; 2]; 3] {
+        match self {
+            DecodeMatrix::FccYiq => [[0.9563, 0.6210], [-0.2721, -0.6474], [-1.1069, 1.7046]],
+        }
+    }
+
+    /// Return a matrix converting a YIQ vector into RGB.
+    pub fn yiq_to_rgb(&self) -> Matrix3<f32> {
+        let k = self.iq_coefficients();
+        #[rustfmt::skip]
+        let m = Matrix3::new(
+            1.0, 1.0, 1.0,
+            k[0][0] as f32, k[1][0] as f32, k[2][0] as f32,
+            k[0][1] as f32, k[1][1] as f32, k[2][1] as f32,
+        );
+        m
+    }
+}
+
+/// The display primaries the decoded RGB is assumed to be in.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Primaries {
+    /// Pass decoded RGB through unchanged.
+    None,
+    /// The original 1953 NTSC primaries with illuminant C.
+    Ntsc1953,
+    /// SMPTE-C phosphors with a D65 white point.
+    SmpteC,
+}
+
+impl FromStr for Primaries {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Primaries::None),
+            "ntsc1953" | "ntsc" => Ok(Primaries::Ntsc1953),
+            "smpte-c" | "smptec" => Ok(Primaries::SmpteC),
+            _ => Err("Bad value for primaries, expected none, ntsc1953 or smpte-c".to_string()),
+        }
+    }
+}
+
+/// The phosphor of a monochrome monitor.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Phosphor {
+    White,
+    /// P31 green, as on the IBM 5151 and many Hercules monitors.
+    Green,
+    Amber,
+}
+
+impl FromStr for Phosphor {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "white" => Ok(Phosphor::White),
+            "green" | "p31" => Ok(Phosphor::Green),
+            "amber" => Ok(Phosphor::Amber),
+            _ => Err("Bad value for phosphor, expected white, green or amber".to_string()),
+        }
+    }
+}
+
+impl Phosphor {
+    /// Return the linear sRGB color of the phosphor at full brightness, or None for white.
+    fn tint(&self) -> Option<Vector3<f32>> {
+        let xy = match self {
+            Phosphor::White => return None,
+            Phosphor::Green => P31_GREEN,
+            Phosphor::Amber => AMBER,
+        };
+        // Clip to the sRGB gamut and scale so the brightest channel is 1.0.
+        let rgb = rgb_to_xyz(SRGB_PRIMARIES, WHITE_D65).invert().unwrap() * xy_to_xyz(xy);
+        let rgb = rgb.map(|v| v.max(0.0));
+        let max = rgb.x.max(rgb.y).max(rgb.z);
+        (rgb / max).cast::<f32>()
+    }
+}
+
+/// Output colorimetry. Decoded RGB is treated as gamma-encoded for a CRT with the given
+/// primaries, linearized, converted to sRGB primaries and then encoded with the sRGB transfer
+/// function, or left as linear light.
+#[derive(Copy, Clone, Debug)]
+pub struct Colorimetry {
+    pub matrix: DecodeMatrix,
+    pub linear: bool,
+    to_srgb: Option<Matrix3<f32>>,
+    phosphor: Option<Vector3<f32>>,
+}
+
+impl Default for Colorimetry {
+    fn default() -> Self {
+        Colorimetry::new(DecodeMatrix::FccYiq, Primaries::None, false)
+    }
+}
+
+impl Colorimetry {
+    pub fn new(matrix: DecodeMatrix, primaries: Primaries, linear: bool) -> Self {
+        let to_srgb = match primaries {
+            Primaries::None => None,
+            Primaries::Ntsc1953 => Some(primaries_to_srgb(NTSC_1953_PRIMARIES, WHITE_C)),
+            Primaries::SmpteC => Some(primaries_to_srgb(SMPTE_C_PRIMARIES, WHITE_D65)),
+        };
+
+        Self {
+            matrix,
+            linear,
+            to_srgb,
+            phosphor: None,
+        }
+    }
+
+    /// Display decoded luma on a monochrome monitor with the given phosphor. The primaries are
+    /// ignored, and the red channel of decoded colors is taken as luma.
+    pub fn with_phosphor(mut self, phosphor: Phosphor) -> Self {
+        self.phosphor = phosphor.tint();
+        self
+    }
+
+    /// Returns true if `apply` would leave colors unchanged.
+    pub fn is_identity(&self) -> bool {
+        self.to_srgb.is_none() && self.phosphor.is_none() && !self.linear
+    }
+
+    /// Convert a decoded, gamma-encoded RGB color in the range 0-1 to the output colorimetry.
+    /// Values outside of 0-1 are carried through.
+    pub fn apply(&self, rgb: Vector3<f32>) -> Vector3<f32> {
+        if self.is_identity() {
+            return rgb;
+        }
+
+        let mut lin = rgb.map(crt_eotf);
+        if let Some(tint) = self.phosphor {
+            lin = tint * lin.x;
+        } else if let Some(m) = self.to_srgb {
+            lin = m * lin;
+        }
+
+        if self.linear {
+            lin
+        } else {
+            lin.map(srgb_oetf)
+        }
+    }
+}
+
+/// Sign-preserving CRT transfer function, so out-of-gamut negative values survive.
+#[inline]
+fn crt_eotf(v: f32) -> f32 {
+    v.signum() * v.abs().powf(CRT_GAMMA)
+}
+
+/// Sign-preserving sRGB encoding function.
+#[inline]
+fn srgb_oetf(v: f32) -> f32 {
+    let a = v.abs();
+    let e = if a <= 0.0031308 {
+        a * 12.92
+    } else {
+        1.055 * a.powf(1.0 / 2.4) - 0.055
+    };
+    e.copysign(v)
+}
+
+fn xy_to_xyz((x, y): (f64, f64)) -> Vector3<f64> {
+    Vector3::new(x / y, 1.0, (1.0 - x - y) / y)
+}
+
+/// Build the RGB to XYZ matrix for a set of primaries and a white point.
+fn rgb_to_xyz(primaries: [(f64, f64); 3], white: (f64, f64)) -> Matrix3<f64> {
+    let p = Matrix3::from_cols(
+        xy_to_xyz(primaries[0]),
+        xy_to_xyz(primaries[1]),
+        xy_to_xyz(primaries[2]),
+    );
+    let s = p.invert().expect("degenerate primaries") * xy_to_xyz(white);
+
+    Matrix3::from_cols(p.x * s.x, p.y * s.y, p.z * s.z)
+}
+
+/// Build a Bradford chromatic adaptation matrix between two white points.
+fn bradford_adapt(src: (f64, f64), dst: (f64, f64)) -> Matrix3<f64> {
+    #[rustfmt::skip]
+    let mb = Matrix3::new(
+        BRADFORD[0][0], BRADFORD[1][0], BRADFORD[2][0],
+        BRADFORD[0][1], BRADFORD[1][1], BRADFORD[2][1],
+        BRADFORD[0][2], BRADFORD[1][2], BRADFORD[2][2],
+    );
+    let src_lms = mb * xy_to_xyz(src);
+    let dst_lms = mb * xy_to_xyz(dst);
+    let scale = Matrix3::from_diagonal(Vector3::new(
+        dst_lms.x / src_lms.x,
+        dst_lms.y / src_lms.y,
+        dst_lms.z / src_lms.z,
+    ));
+
+    mb.invert().unwrap() * scale * mb
+}
+
+/// Build a linear-light matrix converting from the given primaries to sRGB primaries.
+fn primaries_to_srgb(primaries: [(f64, f64); 3], white: (f64, f64)) -> Matrix3<f32> {
+    let src = rgb_to_xyz(primaries, white);
+    let dst = rgb_to_xyz(SRGB_PRIMARIES, WHITE_D65);
+    let m = dst.invert().unwrap() * bradford_adapt(white, WHITE_D65) * src;
+
+    m.cast::<f32>().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn primaries_keep_reference_white() {
+        for (primaries, white) in [(NTSC_1953_PRIMARIES, WHITE_C), (SMPTE_C_PRIMARIES, WHITE_D65)] {
+            let rgb = primaries_to_srgb(primaries, white) * Vector3::new(1.0, 1.0, 1.0);
+            for v in [rgb.x, rgb.y, rgb.z] {
+                assert!((v - 1.0).abs() < 1.0e-3, "white maps to {:?}", rgb);
+            }
+        }
+    }
+}
@@ -3,16 +3,127 @@
 
 use std::time::Instant;
 
+use serde::{Deserialize, Serialize};
+
+use crate::analog::{process_cga_composite_analog, AnalogModel};
+use crate::reenigne_composite::INTENSITY;
+
 pub const EDGE_RESPONSE: f32 = 0.80;
 pub const INTENSITY_GAIN: f32 = 0.25;
 pub const INTENSITY_GAIN_INT: u8 = 64;
 pub const LUMA_ATTENUATE: f32 = 0.75;
 
-#[derive(Copy, Clone, PartialEq)]
+pub const HDOT_CLOCK: f64 = 14_318_180.0; // CGA pixel clock in Hz, 4x the color subcarrier
+pub const LINE_HDOTS: u32 = 912; // Total hdots per scanline, including blanking
+pub const MAX_SAMPLES_PER_HDOT: u32 = 16;
+
+/// The model used to turn CGA color indices into a composite signal.
+#[derive(Copy, Clone, Debug)]
+pub enum EncoderModel {
+    /// Ideal color clock waveforms, with edges aligned to the color clock scaled by the
+    /// model's `edge_response`.
+    Digital,
+    /// RC filtered output stage with per-color skew, see the analog module.
+    Analog(AnalogModel),
+}
+
+/// Signal levels of the composite encoders. These default to the hand-tuned constants and can
+/// be fitted to captures of real hardware with the calibrate subcommand.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelParams {
+    /// Level of the chroma output relative to bright white.
+    pub luma_attenuate: f32,
+    /// Level added by the intensity bit.
+    pub intensity_gain: f32,
+    /// Scale of edges aligned to the color clock; 1.0 for square edges.
+    pub edge_response: f32,
+    /// Intensity levels of the Reenigne decoder's output stage model.
+    pub intensity: [f64; 4],
+}
+
+impl Default for ModelParams {
+    fn default() -> Self {
+        Self {
+            luma_attenuate: LUMA_ATTENUATE,
+            intensity_gain: INTENSITY_GAIN,
+            edge_response: 1.0,
+            intensity: INTENSITY,
+        }
+    }
+}
+
+impl ModelParams {
+    /// Load model parameters from a JSON profile written by the calibrate subcommand.
+    pub fn load(path: &std::path::Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&text).map_err(|e| e.to_string())
+    }
+
+    /// Write the parameters as a JSON profile.
+    pub fn save(&self, path: &std::path::Path) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, json).map_err(|e| e.to_string())
+    }
+}
+
+/// A composite signal with a fixed number of samples per line.
+pub struct CompositeSignal {
+    pub samples: Vec<f32>,
+    pub width: u32,
+    pub height: u32,
+    pub samples_per_hdot: u32,
+}
+
+impl CompositeSignal {
+    pub fn new(width: u32, height: u32, samples_per_hdot: u32) -> Self {
+        Self {
+            samples: vec![0.0; (width * height) as usize],
+            width,
+            height,
+            samples_per_hdot,
+        }
+    }
+
+    /// Encode a CGA index image of one pixel per hdot into a composite signal of
+    /// `samples_per_hdot` samples per pixel.
+    pub fn encode(
+        cga_buf: &[u8],
+        img_w: u32,
+        img_h: u32,
+        samples_per_hdot: u32,
+        model: &EncoderModel,
+        params: &ModelParams,
+    ) -> Self {
+        let spd = samples_per_hdot;
+        let mut signal = CompositeSignal::new(img_w * spd, img_h, spd);
+        match model {
+            EncoderModel::Digital => {
+                process_cga_composite(cga_buf, &mut signal.samples, img_w, img_h, spd, params)
+            }
+            EncoderModel::Analog(analog) => {
+                process_cga_composite_analog(cga_buf, &mut signal.samples, img_w, img_h, spd, analog, params)
+            }
+        }
+        signal
+    }
+
+    /// Sample rate of the signal in Hz.
+    pub fn sample_rate(&self) -> f64 {
+        HDOT_CLOCK * self.samples_per_hdot as f64
+    }
+
+    /// Quantize the signal to 8 bits for preview.
+    pub fn to_l8(&self) -> Vec<u8> {
+        self.samples.iter().map(|s| (s * 255.0).clamp(0.0, 255.0) as u8).collect()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct RGBColor {
-    r: u8,
-    g: u8,
-    b: u8
+    pub r: u8,
+    pub g: u8,
+    pub b: u8
 }
 
 #[derive(Copy, Clone)]
@@ -81,7 +192,7 @@ pub const COLOR_GEN_EDGES_HALF: [[bool; 8]; 8] = [
 
 /// Return the square of the distance between two colors in RGB space. Since we are just 
 /// comparing the magnitude, we don't need to take the square root.
-fn rgb_distance_squared(a: RGBColor, b: RGBColor) -> i32 {
+pub fn rgb_distance_squared(a: RGBColor, b: RGBColor) -> i32 {
 
     let dr = a.r as i32 - b.r as i32;
     let dg = a.g as i32 - b.g as i32;
@@ -108,11 +219,9 @@ pub fn rgb_to_cga(rgb: RGBColor) -> u8 {
         return 15;
     }    
 
-    for i in 0..16 {
-
-        let rgb_palette = CGA_RGB_TABLE[i];
+    for (i, rgb_palette) in CGA_RGB_TABLE.iter().enumerate() {
 
-        let temp_distance = rgb_distance_squared(rgb, rgb_palette);
+        let temp_distance = rgb_distance_squared(rgb, *rgb_palette);
 
         if temp_distance < color_distance_rgb {
             color_index = i;
@@ -126,7 +235,7 @@ pub fn rgb_to_cga(rgb: RGBColor) -> u8 {
 /// Return the hdot number (0-3) for the given x position.
 #[inline]
 pub fn get_cycle_hdot(x: i32) -> usize {
-    (x % 4).abs() as usize
+    (x % 4).unsigned_abs() as usize
 }
 
 /// Convert a slice of RGBA image data into a slice of CGA palette indices.
@@ -140,7 +249,7 @@ pub fn convert_rgb_to_cga_idx(img_in: &mut[u8], cga_out: &mut[u8], img_w: u32, i
 
             let cga_idx = rgb_to_cga(
                 RGBColor {
-                    r: img_in[so + 0],
+                    r: img_in[so],
                     g: img_in[so + 1],
                     b: img_in[so + 2],
                 }
@@ -150,22 +259,38 @@ pub fn convert_rgb_to_cga_idx(img_in: &mut[u8], cga_out: &mut[u8], img_w: u32, i
     }
 }
 
-/// Convert a 640 pixel wide, 16 color CGA image into a 1280 pixel wide Composite image.
-/// The input image should be a slice of RGBA pixel values.
-/// The output image should be a slice of u8 values to receive the grayscale composite signal.
-pub fn process_cga_composite(img_in: &mut [u8], img_out: &mut [u8], img_w: u32, img_h: u32) {
+/// Return the average of a pair of half-hdot values over sample `h` of `samples_per_hdot`
+/// equal samples spanning the hdot.
+#[inline]
+pub fn resample_halves(halves: [f32; 2], h: u32, samples_per_hdot: u32) -> f32 {
+    let start = h as f32 / samples_per_hdot as f32;
+    let end = (h + 1) as f32 / samples_per_hdot as f32;
+    let first = (end.min(0.5) - start).max(0.0);
+    let second = (end - start.max(0.5)).max(0.0);
 
-    let mut cga_buf: Vec<u8> = vec![0; (img_w * img_h) as usize];
+    (halves[0] * first + halves[1] * second) * samples_per_hdot as f32
+}
 
-    //let mut sample_slice: [u8; WINDOW_SIZE as usize] = [0; WINDOW_SIZE as usize];
+/// Convert a 16 color CGA image into a Composite signal of `samples_per_hdot` samples per
+/// pixel. The color generator is modelled in half-hdots, which are resampled to the output
+/// rate; at 2 samples per hdot each sample is one half-hdot.
+/// The input image should be a slice of CGA color indices.
+/// The output should be a slice of f32 values to receive the composite signal, where 0.0 is
+/// black and 1.0 is bright white.
+///
+/// Edges of the color clock are scaled by the model's `edge_response`. Use EDGE_RESPONSE to
+/// model the slew of the color generator, or 1.0 for square edges.
+pub fn process_cga_composite(
+    cga_buf: &[u8],
+    img_out: &mut [f32],
+    img_w: u32,
+    img_h: u32,
+    samples_per_hdot: u32,
+    params: &ModelParams,
+) {
 
     let bench_t = Instant::now();
 
-    convert_rgb_to_cga_idx(img_in, &mut cga_buf, img_w, img_h);
-    
-    let ms = (Instant::now() - bench_t).as_millis();
-    log::debug!("RGBA->CGA conversion time took: {}", ms);
-
     for y in 0..img_h {
         for x in 0..img_w {
             //get_sample_slice_cga(&cga_buf, img_w, img_h, x, y, &mut sample_slice);
@@ -178,7 +303,7 @@ pub fn process_cga_composite(img_in: &mut [u8], img_out: &mut [u8], img_w: u32,
             // Convert 0-15 color range to 0-7
             let color = cga_buf[src_o];
             let next_color = if x < (img_w - 1) {
-                cga_buf[src_o + 1 as usize] % 8
+                cga_buf[src_o + 1] % 8
             }
             else {
                 0
@@ -187,32 +312,33 @@ pub fn process_cga_composite(img_in: &mut [u8], img_out: &mut [u8], img_w: u32,
             let is_bright = color > 7;
 
             let hdot = get_cycle_hdot(x as i32);
+            let mut halves = [0.0; 2];
 
             for h in 0..2usize {
 
                 let mut attenuate = false;
                 
-                let mut hhdot_value = COLOR_GEN_HALF[base_color as usize][(hdot * 2 + h) as usize];
+                let mut hhdot_value = COLOR_GEN_HALF[base_color as usize][hdot * 2 + h];
                 let next_hhdot_value = match h {
                     0 => {
-                        COLOR_GEN_HALF[base_color as usize][((hdot * 2 + h) + 1) % 8 as usize ]
+                        COLOR_GEN_HALF[base_color as usize][((hdot * 2 + h) + 1) % 8]
                     }
                     _ => {
-                        COLOR_GEN_HALF[next_color as usize][((hdot * 2 + h) + 1) % 8 as usize ]   
+                        COLOR_GEN_HALF[next_color as usize][((hdot * 2 + h) + 1) % 8]   
                     }
                 };
-                let hhdot_is_edge = COLOR_GEN_EDGES_HALF[base_color as usize][(hdot * 2 + h) as usize];
+                let hhdot_is_edge = COLOR_GEN_EDGES_HALF[base_color as usize][hdot * 2 + h];
 
                 if hhdot_value == 1.0 && last_hhdot_value == 0.0 {
                     // Signal is rising.
-                    if hhdot_is_edge == true {
+                    if hhdot_is_edge {
                         // Signal is rising with rising edge of color clock. Attenuate edge slew.
                         attenuate = true;
                     }
                 }
                 else if hhdot_value == 1.0 && next_hhdot_value == 0.0 {
                     // Signal is falling on next hhdot.
-                    if hhdot_is_edge == true {
+                    if hhdot_is_edge {
                         // Signal is falling with falling edge of color clock. Attenuate edge slew.
                         attenuate = true;
                     }
@@ -221,21 +347,27 @@ pub fn process_cga_composite(img_in: &mut [u8], img_out: &mut [u8], img_w: u32,
                 last_hhdot_value = hhdot_value;
 
                 if attenuate {
-                    hhdot_value *= EDGE_RESPONSE;
+                    hhdot_value *= params.edge_response;
                 }
 
-                hhdot_value *= LUMA_ATTENUATE;
+                hhdot_value *= params.luma_attenuate;
 
                 if is_bright {
-                    hhdot_value += INTENSITY_GAIN;
+                    hhdot_value += params.intensity_gain;
                 }
 
-                let composite_u8 = (hhdot_value * 255.0) as u8;
-                let dst_o = ((y * img_w * 2) + (x * 2)) as usize;
-                img_out[dst_o + h] = composite_u8;
+                halves[h] = hhdot_value;
+            }
+
+            let dst_o = ((y * img_w + x) * samples_per_hdot) as usize;
+            for h in 0..samples_per_hdot {
+                img_out[dst_o + h as usize] = resample_halves(halves, h, samples_per_hdot);
             }
         }
     }
+
+    let us = (Instant::now() - bench_t).as_micros();
+    log::debug!("Composite conversion took: {} milliseconds", us as f32 / 1000.0 );
 }
 
 
@@ -271,7 +403,7 @@ pub fn process_cga_composite_int(img_in: &mut [u8], img_out: &mut [u8], img_w: u
             // Convert 0-15 color range to 0-7
             let color = cga_buf[src_o];
             let next_color = if x < (img_w - 1) {
-                cga_buf[src_o + 1 as usize] % 8
+                cga_buf[src_o + 1] % 8
             }
             else {
                 0
@@ -285,27 +417,27 @@ pub fn process_cga_composite_int(img_in: &mut [u8], img_out: &mut [u8], img_w: u
 
                 let mut attenuate = false;
                 
-                let mut hhdot_value = COLOR_GEN_HALF_INT[base_color as usize][(hdot * 2 + h) as usize];
+                let mut hhdot_value = COLOR_GEN_HALF_INT[base_color as usize][hdot * 2 + h];
                 let next_hhdot_value = match h {
                     0 => {
-                        COLOR_GEN_HALF_INT[base_color as usize][((hdot * 2 + h) + 1) % 8 as usize ]
+                        COLOR_GEN_HALF_INT[base_color as usize][((hdot * 2 + h) + 1) % 8]
                     }
                     _ => {
-                        COLOR_GEN_HALF_INT[next_color as usize][((hdot * 2 + h) + 1) % 8 as usize ]   
+                        COLOR_GEN_HALF_INT[next_color as usize][((hdot * 2 + h) + 1) % 8]   
                     }
                 };
-                let hhdot_is_edge = COLOR_GEN_EDGES_HALF[base_color as usize][(hdot * 2 + h) as usize];
+                let hhdot_is_edge = COLOR_GEN_EDGES_HALF[base_color as usize][hdot * 2 + h];
 
                 if hhdot_value == 255 && last_hhdot_value == 0 {
                     // Signal is rising.
-                    if hhdot_is_edge == true {
+                    if hhdot_is_edge {
                         // Signal is rising with rising edge of color clock. Attenuate edge slew.
                         attenuate = true;
                     }
                 }
                 else if hhdot_value == 255 && next_hhdot_value == 0 {
                     // Signal is falling on next hhdot.
-                    if hhdot_is_edge == true {
+                    if hhdot_is_edge {
                         // Signal is falling with falling edge of color clock. Attenuate edge slew.
                         attenuate = true;
                     }
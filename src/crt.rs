@@ -0,0 +1,328 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    CRT presentation
+
+    Makes a scaled, decoded image look like it is on a monitor: the electron
+    beam spreads wider horizontally and vertically as it gets brighter,
+    leaving dark gaps between dim scanlines, the picture bows out on a
+    curved tube, the phosphor mask shows through, and bright areas glow.
+    The stages run in that order on the CPU, in output pixels, and work in
+    unclamped floats like the rest of the output path.
+*/
+
+use std::str::FromStr;
+
+use crate::field::scanlines;
+use crate::output::{FloatImage, FrameSize};
+use crate::scale::Placement;
+
+pub const DEFAULT_SCANLINES: f32 = 0.7;
+pub const DEFAULT_MASK_STRENGTH: f32 = 0.3;
+pub const DEFAULT_MASK_PITCH: u32 = 3;
+pub const DEFAULT_BEAM_SPREAD: f32 = 0.75;
+pub const DEFAULT_BLOOM: f32 = 0.15;
+pub const DEFAULT_CURVATURE: f32 = 0.03;
+
+// Vertical size of the beam, as a standard deviation in scanlines, at black and at full brightness.
+const BEAM_SIGMA_DIM: f32 = 0.2;
+const BEAM_SIGMA_BRIGHT: f32 = 0.45;
+// Standard deviation of the bloom blur as a fraction of the picture width.
+const BLOOM_WIDTH: f32 = 1.0 / 80.0;
+
+/// Phosphor mask pattern.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MaskType {
+    None,
+    /// Continuous vertical stripes, as on a Trinitron.
+    Aperture,
+    /// Triads of dots, offset on alternate lines.
+    Shadow,
+    /// Stripes broken into slots, staggered between neighbouring triads.
+    Slot,
+}
+
+impl FromStr for MaskType {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(MaskType::None),
+            "aperture" => Ok(MaskType::Aperture),
+            "shadow" => Ok(MaskType::Shadow),
+            "slot" => Ok(MaskType::Slot),
+            _ => Err("Bad value for mask, expected none, aperture, shadow or slot".to_string()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct CrtSettings {
+    /// Darkening between scanlines, from 0 for none to 1 for a full beam profile.
+    pub scanlines: f32,
+    pub mask: MaskType,
+    /// How much the mask dims the phosphors of the other two colors, 0-1.
+    pub mask_strength: f32,
+    /// Width of one red, green and blue triad in output pixels.
+    pub mask_pitch: u32,
+    /// Horizontal spread of a full brightness beam, as a standard deviation in decoded pixels.
+    pub beam_spread: f32,
+    /// Amount of blurred glow added on top of the picture.
+    pub bloom: f32,
+    /// How far the corners of the picture bow outward, relative to its half size.
+    pub curvature: f32,
+}
+
+impl Default for CrtSettings {
+    fn default() -> Self {
+        Self {
+            scanlines: DEFAULT_SCANLINES,
+            mask: MaskType::Aperture,
+            mask_strength: DEFAULT_MASK_STRENGTH,
+            mask_pitch: DEFAULT_MASK_PITCH,
+            beam_spread: DEFAULT_BEAM_SPREAD,
+            bloom: DEFAULT_BLOOM,
+            curvature: DEFAULT_CURVATURE,
+        }
+    }
+}
+
+#[inline]
+fn luminance(p: [f32; 3]) -> f32 {
+    0.299 * p[0] + 0.587 * p[1] + 0.114 * p[2]
+}
+
+#[inline]
+fn gaussian(x: f32, sigma: f32) -> f32 {
+    (-(x * x) / (2.0 * sigma * sigma)).exp()
+}
+
+impl CrtSettings {
+    /// Run a scaled image through the CRT stages. `source` is the size of the decoded image
+    /// before scaling and `picture` where it was placed in the scaled image.
+    pub fn apply(&self, img: FloatImage, source: FrameSize, picture: Placement) -> FloatImage {
+        let mut img = img;
+        if self.beam_spread > 0.0 {
+            let pixel_width = picture.width as f32 / source.width as f32;
+            img = self.spread(&img, self.beam_spread * pixel_width);
+        }
+        if self.scanlines > 0.0 {
+            img = self.scanline(&img, picture, scanlines(source.height));
+        }
+        if self.curvature != 0.0 {
+            img = self.curve(&img, picture);
+        }
+        if self.mask != MaskType::None && self.mask_strength > 0.0 {
+            self.apply_mask(&mut img);
+        }
+        if self.bloom > 0.0 {
+            img = self.glow(&img, picture.width as f32 * BLOOM_WIDTH);
+        }
+        img
+    }
+
+    /// Spread each pixel along its line, further the brighter it is. The light of every pixel
+    /// is scattered over its neighbours so the total is kept.
+    fn spread(&self, img: &FloatImage, sigma: f32) -> FloatImage {
+        let w = img.width as usize;
+        let mut out = FloatImage::new(img.width, img.height);
+
+        for (line, out_line) in img.pixels.chunks(w).zip(out.pixels.chunks_mut(w)) {
+            for (x, &p) in line.iter().enumerate() {
+                let s = sigma * luminance(p).clamp(0.0, 2.0);
+                if s < 0.1 {
+                    for c in 0..3 {
+                        out_line[x][c] += p[c];
+                    }
+                    continue;
+                }
+
+                let radius = (s * 3.0).ceil() as isize;
+                let first = (x as isize - radius).max(0) as usize;
+                let last = (x as isize + radius).min(w as isize - 1) as usize;
+                let weights: Vec<f32> = (first..=last).map(|t| gaussian(t as f32 - x as f32, s)).collect();
+                let total: f32 = weights.iter().sum();
+
+                for (t, weight) in (first..=last).zip(weights) {
+                    for c in 0..3 {
+                        out_line[t][c] += p[c] * weight / total;
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Redraw the picture as scanlines with a gaussian beam profile whose height grows with
+    /// brightness. Each output line mixes the two nearest scanlines.
+    fn scanline(&self, img: &FloatImage, picture: Placement, lines: u32) -> FloatImage {
+        let w = img.width as usize;
+        let line_height = picture.height as f32 / lines as f32;
+        // The output line through the center of each scanline.
+        let centers: Vec<usize> = (0..lines)
+            .map(|l| picture.y as usize + (((l as f32 + 0.5) * line_height) as usize).min(picture.height as usize - 1))
+            .collect();
+
+        let mut out = img.clone();
+        for y in 0..picture.height as usize {
+            let v = (y as f32 + 0.5) / line_height;
+            let above = (v - 0.5).floor() as i64;
+            let o = (picture.y as usize + y) * w;
+
+            for x in picture.x as usize..(picture.x + picture.width) as usize {
+                let mut beam = [0.0f32; 3];
+                for l in [above, above + 1] {
+                    if l < 0 || l >= lines as i64 {
+                        continue;
+                    }
+                    let p = img.pixels[centers[l as usize] * w + x];
+                    let sigma = BEAM_SIGMA_DIM + (BEAM_SIGMA_BRIGHT - BEAM_SIGMA_DIM) * luminance(p).clamp(0.0, 1.0);
+                    let weight = gaussian(v - (l as f32 + 0.5), sigma);
+                    for c in 0..3 {
+                        beam[c] += p[c] * weight;
+                    }
+                }
+
+                let q = &mut out.pixels[o + x];
+                for c in 0..3 {
+                    q[c] += (beam[c] - q[c]) * self.scanlines;
+                }
+            }
+        }
+        out
+    }
+
+    /// Barrel distort the picture, leaving black where the bowed edges pull away from it.
+    fn curve(&self, img: &FloatImage, picture: Placement) -> FloatImage {
+        let w = img.width as usize;
+        let (pw, ph) = (picture.width as f32, picture.height as f32);
+        let aspect = pw / ph;
+
+        let mut out = img.clone();
+        for y in 0..picture.height as usize {
+            for x in 0..picture.width as usize {
+                // Position on the tube, -1 to 1 across the picture, and its squared radius with
+                // 1 at the corners.
+                let u = (x as f32 + 0.5) / pw * 2.0 - 1.0;
+                let v = (y as f32 + 0.5) / ph * 2.0 - 1.0;
+                let r2 = ((u * aspect).powi(2) + v * v) / (aspect * aspect + 1.0);
+                let f = 1.0 + self.curvature * r2;
+                let (su, sv) = (u * f, v * f);
+
+                let p = if su.abs() > 1.0 || sv.abs() > 1.0 {
+                    [0.0; 3]
+                } else {
+                    let sx = picture.x as f32 + (su + 1.0) * 0.5 * pw - 0.5;
+                    let sy = picture.y as f32 + (sv + 1.0) * 0.5 * ph - 0.5;
+                    sample_bilinear(img, sx, sy, picture)
+                };
+                out.pixels[(picture.y as usize + y) * w + picture.x as usize + x] = p;
+            }
+        }
+        out
+    }
+
+    /// Multiply by the phosphor mask. The mask is scaled up so it doesn't darken the picture
+    /// overall.
+    fn apply_mask(&self, img: &mut FloatImage) {
+        let pitch = self.mask_pitch.max(1) as usize;
+        let dim = 1.0 - self.mask_strength.clamp(0.0, 1.0);
+        let gain = 3.0 / (1.0 + 2.0 * dim);
+        let slot_height = pitch * 2;
+        let w = img.width as usize;
+
+        for (y, line) in img.pixels.chunks_mut(w).enumerate() {
+            let offset = match self.mask {
+                MaskType::Shadow => (y % 2) * pitch / 2,
+                _ => 0,
+            };
+            for (x, p) in line.iter_mut().enumerate() {
+                let phosphor = ((x + offset) % pitch) * 3 / pitch;
+                let in_gap = self.mask == MaskType::Slot
+                    && (y + ((x / pitch) % 2) * slot_height / 2) % slot_height == slot_height - 1;
+                for (c, v) in p.iter_mut().enumerate() {
+                    let lit = c == phosphor && !in_gap;
+                    *v *= if lit { gain } else { dim * gain };
+                }
+            }
+        }
+    }
+
+    /// Add a wide blur of the image on top of it.
+    fn glow(&self, img: &FloatImage, sigma: f32) -> FloatImage {
+        let radius = (sigma * 3.0).ceil() as isize;
+        let kernel: Vec<f32> = (-radius..=radius).map(|t| gaussian(t as f32, sigma)).collect();
+        let total: f32 = kernel.iter().sum();
+        let kernel: Vec<f32> = kernel.iter().map(|k| k / total).collect();
+
+        let (w, h) = (img.width as isize, img.height as isize);
+        let blur = |src: &FloatImage, horizontal: bool| {
+            let mut out = FloatImage::new(src.width, src.height);
+            for y in 0..h {
+                for x in 0..w {
+                    let mut sum = [0.0f32; 3];
+                    for (k, weight) in (-radius..=radius).zip(&kernel) {
+                        let (sx, sy) = if horizontal {
+                            ((x + k).clamp(0, w - 1), y)
+                        } else {
+                            (x, (y + k).clamp(0, h - 1))
+                        };
+                        let p = src.pixels[(sy * w + sx) as usize];
+                        for c in 0..3 {
+                            sum[c] += p[c] * weight;
+                        }
+                    }
+                    out.pixels[(y * w + x) as usize] = sum;
+                }
+            }
+            out
+        };
+        let blurred = blur(&blur(img, true), false);
+
+        let mut out = img.clone();
+        for (p, b) in out.pixels.iter_mut().zip(&blurred.pixels) {
+            for c in 0..3 {
+                p[c] += b[c] * self.bloom;
+            }
+        }
+        out
+    }
+}
+
+/// Sample the image between pixels, keeping within the picture.
+fn sample_bilinear(img: &FloatImage, x: f32, y: f32, picture: Placement) -> [f32; 3] {
+    let w = img.width as usize;
+    let x = x.clamp(picture.x as f32, (picture.x + picture.width - 1) as f32);
+    let y = y.clamp(picture.y as f32, (picture.y + picture.height - 1) as f32);
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let x1 = (x0 + 1).min((picture.x + picture.width - 1) as usize);
+    let y1 = (y0 + 1).min((picture.y + picture.height - 1) as usize);
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let lerp = |a: [f32; 3], b: [f32; 3], f: f32| [0, 1, 2].map(|c| a[c] * (1.0 - f) + b[c] * f);
+    let top = lerp(img.pixels[y0 * w + x0], img.pixels[y0 * w + x1], fx);
+    let bottom = lerp(img.pixels[y1 * w + x0], img.pixels[y1 * w + x1], fx);
+    lerp(top, bottom, fy)
+}
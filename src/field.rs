@@ -0,0 +1,113 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    Field modeling
+
+    The CGA sends 262 line non-interlaced fields: 200 active lines, the
+    overscan border below and above them, and the vertical blanking interval
+    around vertical sync. A field is assembled from CGA color indices before
+    decoding, so the border lines go through the composite decoders like the
+    picture. Lines are ordered the way a capture of the field sees them,
+    starting at vertical sync, with the same timings as the full frame
+    waveform.
+
+    Each field retraces the same lines, so on a CRT with room for 480 lines
+    there are dark gaps between them, the familiar look of 240p video.
+*/
+
+use crate::output::FloatImage;
+use crate::waveform::{FRAME_LINES, VBLANK_LINES, VSYNC_START};
+
+/// Return the number of scanlines in an image of the given height. A field has at most 262
+/// lines, so anything taller is line doubled.
+pub fn scanlines(height: u32) -> u32 {
+    if height > FRAME_LINES {
+        height / 2
+    } else {
+        height
+    }
+}
+
+/// Place the active lines of an image of CGA color indices in a whole field, FRAME_LINES
+/// high. Line doubled images contribute every other line.
+pub fn assemble(cga_buf: &[u8], width: u32, height: u32, border: u8) -> Result<Vec<u8>, String> {
+    let lines = scanlines(height);
+    if lines > VSYNC_START {
+        return Err(format!("An image of {} lines doesn't fit in a field of {} lines", lines, FRAME_LINES));
+    }
+    let step = (height / lines) as usize;
+    let w = width as usize;
+
+    let mut field = Vec::with_capacity(w * FRAME_LINES as usize);
+    for row in 0..FRAME_LINES {
+        let line = (VSYNC_START + row) % FRAME_LINES;
+        if line < lines {
+            let start = line as usize * step * w;
+            field.extend_from_slice(&cga_buf[start..start + w]);
+        } else if (VSYNC_START..VSYNC_START + VBLANK_LINES).contains(&line) {
+            // Blanking is at the black level.
+            field.extend(std::iter::repeat_n(0, w));
+        } else {
+            field.extend(std::iter::repeat_n(border, w));
+        }
+    }
+    Ok(field)
+}
+
+/// Return the height of an image of the given height after line_gaps.
+pub fn gapped_height(height: u32) -> u32 {
+    if height > FRAME_LINES {
+        height
+    } else {
+        height * 2
+    }
+}
+
+/// Show each scanline as two output lines, the second darkened by `darkness` from 0 to 1.
+/// Line doubled images already have two lines per scanline and just have the second darkened.
+pub fn line_gaps(img: FloatImage, darkness: f32) -> FloatImage {
+    let w = img.width as usize;
+    let gap = 1.0 - darkness.clamp(0.0, 1.0);
+    let darken = |line: &[[f32; 3]]| line.iter().map(|p| p.map(|v| v * gap)).collect::<Vec<_>>();
+
+    let pixels = if img.height > FRAME_LINES {
+        img.pixels
+            .chunks(w)
+            .enumerate()
+            .flat_map(|(y, line)| if y % 2 == 1 { darken(line) } else { line.to_vec() })
+            .collect()
+    } else {
+        img.pixels
+            .chunks(w)
+            .flat_map(|line| line.iter().copied().chain(darken(line)))
+            .collect()
+    };
+    FloatImage {
+        width: img.width,
+        height: gapped_height(img.height),
+        pixels,
+    }
+}
@@ -0,0 +1,543 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    Palette indexed images
+
+    Screenshots are often saved as 4 or 8 bit indexed PNG, GIF, BMP or PCX
+    files whose indices already are CGA colors. Those indices are read as is,
+    without going through RGB, and are used directly when the file's palette
+    agrees with the CGA colors at the same indices. Otherwise the image is
+    matched by color, or remapped through an explicit table.
+
+    4 color PCX files from CGA paint programs record the CGA palette and
+    background color rather than RGB values; their indices are mapped
+    through that palette.
+*/
+
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::composite::{rgb_distance_squared, RGBColor, CGA_RGB_TABLE};
+use crate::rgbi::SourcePalette;
+use crate::vram::{VideoRegisters, COLOR_INTENSE, COLOR_PALETTE, MODE_BW, MODE_ENABLE, MODE_GRAPHICS};
+
+const PCX_HEADER_SIZE: usize = 128;
+const PCX_PALETTE_MARKER: u8 = 0x0C; // Precedes the 256 color palette at the end of a PCX file
+
+/// How the indices of an indexed image become CGA color indices.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum IndexMap {
+    /// Use the indices directly if the file's palette agrees with the CGA colors, otherwise
+    /// match by color.
+    #[default]
+    Auto,
+    /// Use the indices directly.
+    Direct,
+    /// Ignore the indices and match the palette colors.
+    Color,
+    /// The CGA color for each index, in index order.
+    Table(Vec<u8>),
+}
+
+impl FromStr for IndexMap {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(IndexMap::Auto),
+            "direct" => Ok(IndexMap::Direct),
+            "color" => Ok(IndexMap::Color),
+            list => {
+                let table = list
+                    .split(',')
+                    .map(|v| match v.trim().parse::<u8>() {
+                        Ok(c) if c < 16 => Ok(c),
+                        _ => Err(format!("Bad CGA color '{}' in index map, expected 0-15", v.trim())),
+                    })
+                    .collect::<Result<Vec<u8>, String>>()?;
+                Ok(IndexMap::Table(table))
+            }
+        }
+    }
+}
+
+/// An image of palette indices, and the palette.
+#[derive(Clone, Debug)]
+pub struct IndexedImage {
+    pub width: u32,
+    pub height: u32,
+    pub indices: Vec<u8>,
+    pub palette: Vec<RGBColor>,
+    /// The registers of the CGA mode the image was drawn in, when the file records them.
+    pub registers: Option<VideoRegisters>,
+}
+
+impl IndexedImage {
+    /// Expand the image to RGBA through its palette. Indices past the end of the palette are
+    /// black.
+    pub fn to_rgba(&self) -> Vec<u8> {
+        self.indices
+            .iter()
+            .flat_map(|&i| {
+                let c = self.palette.get(i as usize).copied().unwrap_or(RGBColor { r: 0, g: 0, b: 0 });
+                [c.r, c.g, c.b, 0xFF]
+            })
+            .collect()
+    }
+
+    /// Return whether every index used has the color of the same CGA index in `palette`,
+    /// within `tolerance`.
+    pub fn matches_palette(&self, palette: &SourcePalette, tolerance: f32) -> bool {
+        let mut used = [false; 256];
+        for &i in &self.indices {
+            used[i as usize] = true;
+        }
+        let tolerance_sq = (tolerance * tolerance) as i32;
+
+        used.iter().enumerate().filter(|(_, u)| **u).all(|(i, _)| {
+            i < palette.colors.len()
+                && self
+                    .palette
+                    .get(i)
+                    .map(|c| rgb_distance_squared(*c, palette.colors[i]) <= tolerance_sq)
+                    .unwrap_or(false)
+        })
+    }
+
+    /// Use the indices directly as CGA colors.
+    pub fn direct(&self) -> Result<Vec<u8>, String> {
+        match self.indices.iter().find(|&&i| i >= 16) {
+            Some(i) => Err(format!("Image uses index {}, which is not a CGA color", i)),
+            None => Ok(self.indices.clone()),
+        }
+    }
+
+    /// Convert the indices to CGA colors through a table.
+    pub fn remap(&self, table: &[u8]) -> Result<Vec<u8>, String> {
+        self.indices
+            .iter()
+            .map(|&i| {
+                table
+                    .get(i as usize)
+                    .copied()
+                    .ok_or_else(|| format!("Image uses index {} but the index map has {} entries", i, table.len()))
+            })
+            .collect()
+    }
+}
+
+/// Load an image as palette indices, if it is an indexed PNG, GIF, BMP or PCX file. Returns
+/// None for other images, which should be loaded as RGB.
+pub fn load_indexed(path: &Path) -> Result<Option<IndexedImage>, String> {
+    let data = std::fs::read(path).map_err(|e| format!("Couldn't open input image '{:?}': {}", path, e))?;
+
+    let is_pcx = path
+        .extension()
+        .map(|e| e.eq_ignore_ascii_case("pcx"))
+        .unwrap_or(false);
+
+    if data.starts_with(b"\x89PNG") {
+        load_png(&data)
+    } else if data.starts_with(b"GIF8") {
+        load_gif(&data).map(Some)
+    } else if data.starts_with(b"BM") {
+        load_bmp(&data)
+    } else if is_pcx {
+        load_pcx(&data).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Split packed pixels of `bits` bits each into one index per pixel, most significant first.
+fn unpack_row(bytes: &[u8], bits: usize, width: usize) -> impl Iterator<Item = u8> + '_ {
+    let per_byte = 8 / bits;
+    let mask = ((1u16 << bits) - 1) as u8;
+    (0..width).map(move |x| {
+        let shift = 8 - bits * (x % per_byte + 1);
+        (bytes[x / per_byte] >> shift) & mask
+    })
+}
+
+/// Convert packed RGB triples to colors.
+fn rgb_triples(bytes: &[u8]) -> Vec<RGBColor> {
+    bytes
+        .chunks_exact(3)
+        .map(|c| RGBColor {
+            r: c[0],
+            g: c[1],
+            b: c[2],
+        })
+        .collect()
+}
+
+fn load_png(data: &[u8]) -> Result<Option<IndexedImage>, String> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let mut reader = decoder.read_info().map_err(|e| format!("Error reading PNG: {}", e))?;
+
+    let info = reader.info();
+    if info.color_type != png::ColorType::Indexed {
+        return Ok(None);
+    }
+    let (width, height) = (info.width as usize, info.height as usize);
+    let bits = info.bit_depth as usize;
+    let palette = info.palette.as_deref().map(rgb_triples).unwrap_or_default();
+
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buf).map_err(|e| format!("Error reading PNG: {}", e))?;
+
+    let indices = buf[..frame.line_size * height]
+        .chunks(frame.line_size)
+        .flat_map(|row| unpack_row(row, bits, width))
+        .collect();
+
+    Ok(Some(IndexedImage {
+        width: width as u32,
+        height: height as u32,
+        indices,
+        palette,
+        registers: None,
+    }))
+}
+
+/// Read the first frame of a GIF, drawn over the background color.
+fn load_gif(data: &[u8]) -> Result<IndexedImage, String> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(data).map_err(|e| format!("Error reading GIF: {}", e))?;
+
+    let (width, height) = (decoder.width() as usize, decoder.height() as usize);
+    let background = decoder.bg_color().unwrap_or(0) as u8;
+    let global_palette = decoder.global_palette().map(rgb_triples);
+
+    let frame = decoder
+        .read_next_frame()
+        .map_err(|e| format!("Error reading GIF: {}", e))?
+        .ok_or("GIF has no frames")?;
+
+    let mut indices = vec![background; width * height];
+    for fy in 0..frame.height as usize {
+        let y = frame.top as usize + fy;
+        for fx in 0..frame.width as usize {
+            let x = frame.left as usize + fx;
+            let i = frame.buffer[fy * frame.width as usize + fx];
+            if x < width && y < height && Some(i) != frame.transparent {
+                indices[y * width + x] = i;
+            }
+        }
+    }
+
+    let palette = frame
+        .palette
+        .as_deref()
+        .map(rgb_triples)
+        .or(global_palette)
+        .ok_or("GIF has no palette")?;
+
+    Ok(IndexedImage {
+        width: width as u32,
+        height: height as u32,
+        indices,
+        palette,
+        registers: None,
+    })
+}
+
+#[inline]
+fn read_u16(data: &[u8], o: usize) -> u16 {
+    u16::from_le_bytes([data[o], data[o + 1]])
+}
+
+#[inline]
+fn read_u32(data: &[u8], o: usize) -> u32 {
+    u32::from_le_bytes([data[o], data[o + 1], data[o + 2], data[o + 3]])
+}
+
+/// Read an uncompressed 1, 4 or 8 bit BMP. Compressed and truecolor BMPs are left to the
+/// RGB loader.
+fn load_bmp(data: &[u8]) -> Result<Option<IndexedImage>, String> {
+    if data.len() < 26 {
+        return Err("BMP file is truncated".to_string());
+    }
+    let pixel_offset = read_u32(data, 10) as usize;
+    let header_size = read_u32(data, 14) as usize;
+
+    // OS/2 bitmaps have a 12 byte header with 16 bit dimensions and 3 byte palette entries.
+    let (width, height, bits, compression, colors_used, entry_size) = if header_size == 12 {
+        (read_u16(data, 18) as i32, read_u16(data, 20) as i32, read_u16(data, 24), 0, 0, 3)
+    } else if data.len() >= 14 + 40 {
+        (
+            read_u32(data, 18) as i32,
+            read_u32(data, 22) as i32,
+            read_u16(data, 28),
+            read_u32(data, 30),
+            read_u32(data, 46) as usize,
+            4,
+        )
+    } else {
+        return Err("BMP file is truncated".to_string());
+    };
+
+    if !matches!(bits, 1 | 4 | 8) || compression != 0 {
+        return Ok(None);
+    }
+
+    let colors = if colors_used == 0 { 1 << bits } else { colors_used };
+    let table = 14 + header_size;
+    let palette: Vec<RGBColor> = (0..colors)
+        .map_while(|i| data.get(table + i * entry_size..table + i * entry_size + 3))
+        .map(|c| RGBColor {
+            r: c[2],
+            g: c[1],
+            b: c[0],
+        })
+        .collect();
+
+    // Rows are padded to 4 bytes and stored bottom up, unless the height is negative.
+    let (w, h) = (width.unsigned_abs() as usize, height.unsigned_abs() as usize);
+    let stride = (w * bits as usize).div_ceil(32) * 4;
+    if data.len() < pixel_offset + stride * h {
+        return Err("BMP file is truncated".to_string());
+    }
+
+    let mut indices = Vec::with_capacity(w * h);
+    for y in 0..h {
+        let row = if height > 0 { h - 1 - y } else { y };
+        let o = pixel_offset + row * stride;
+        indices.extend(unpack_row(&data[o..o + stride], bits as usize, w));
+    }
+
+    Ok(Some(IndexedImage {
+        width: w as u32,
+        height: h as u32,
+        indices,
+        palette,
+        registers: None,
+    }))
+}
+
+/// Read a PC Paintbrush PCX file with 1, 2, 4 or 8 bit pixels, packed or in bit planes.
+fn load_pcx(data: &[u8]) -> Result<IndexedImage, String> {
+    if data.len() < PCX_HEADER_SIZE || data[0] != 0x0A {
+        return Err("Not a PCX file".to_string());
+    }
+    let version = data[1];
+    let rle = data[2] == 1;
+    let bits = data[3] as usize;
+    let width = (read_u16(data, 8) as usize + 1).saturating_sub(read_u16(data, 4) as usize);
+    let height = (read_u16(data, 10) as usize + 1).saturating_sub(read_u16(data, 6) as usize);
+    let planes = data[65] as usize;
+    let line_bytes = read_u16(data, 66) as usize;
+
+    if !matches!((bits, planes), (1 | 2 | 4 | 8, 1) | (1, 2..=4)) {
+        return Err(format!("Unsupported PCX format: {} bits per pixel in {} planes", bits, planes));
+    }
+
+    // Decode the run length encoding of the whole image; runs may cross scanlines.
+    let scanline = line_bytes * planes;
+    let needed = scanline * height;
+    let mut pixels = Vec::with_capacity(needed);
+    let mut o = PCX_HEADER_SIZE;
+    while pixels.len() < needed && o < data.len() {
+        let b = data[o];
+        o += 1;
+        if rle && b & 0xC0 == 0xC0 {
+            let value = *data.get(o).ok_or("PCX file is truncated")?;
+            o += 1;
+            pixels.extend(std::iter::repeat_n(value, (b & 0x3F) as usize));
+        } else {
+            pixels.push(b);
+        }
+    }
+    if pixels.len() < needed {
+        return Err("PCX file is truncated".to_string());
+    }
+
+    let mut indices = Vec::with_capacity(width * height);
+    for line in pixels[..needed].chunks(scanline) {
+        if planes == 1 {
+            indices.extend(unpack_row(line, bits, width));
+        } else {
+            // One bit per plane, plane 0 holding the low bit.
+            let rows: Vec<Vec<u8>> = line.chunks(line_bytes).map(|p| unpack_row(p, 1, width).collect()).collect();
+            indices.extend((0..width).map(|x| rows.iter().enumerate().fold(0, |acc, (p, row)| acc | (row[x] << p))));
+        }
+    }
+
+    if bits == 2 && planes == 1 {
+        if let Some(regs) = pcx_cga_registers(data) {
+            let (_, colors) = regs.pixel_colors();
+            log::debug!("CGA PCX palette, color select register {:02X}h", regs.color_select);
+            return Ok(IndexedImage {
+                width: width as u32,
+                height: height as u32,
+                indices,
+                palette: colors.iter().map(|&c| CGA_RGB_TABLE[c as usize]).collect(),
+                registers: Some(regs),
+            });
+        }
+    }
+
+    // 256 color images keep their palette at the end of the file, others in the header.
+    let tail = data.len().saturating_sub(769);
+    let palette = if bits == 8 && version >= 5 && data.len() >= PCX_HEADER_SIZE + 769 && data[tail] == PCX_PALETTE_MARKER
+    {
+        rgb_triples(&data[tail + 1..])
+    } else if bits == 1 && planes == 1 && data[16..22].iter().all(|&b| b == 0) {
+        // Many monochrome files leave the header palette empty.
+        vec![RGBColor { r: 0, g: 0, b: 0 }, RGBColor { r: 0xFF, g: 0xFF, b: 0xFF }]
+    } else {
+        rgb_triples(&data[16..64])
+    };
+
+    Ok(IndexedImage {
+        width: width as u32,
+        height: height as u32,
+        indices,
+        palette,
+        registers: None,
+    })
+}
+
+/// Return the CGA registers recorded in the header of a 4 color PCX file. PC Paintbrush stores
+/// the background color in the high nibble of the first palette byte, and the burst, palette
+/// and intensity bits in bits 7, 6 and 5 of the fourth. Files that hold an RGB palette there
+/// instead return None.
+fn pcx_cga_registers(data: &[u8]) -> Option<VideoRegisters> {
+    let header = &data[16..28];
+    let rgb_bytes = header
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != 0 && *i != 3)
+        .any(|(_, &b)| b != 0);
+    if rgb_bytes || header[0] & 0x0F != 0 || header[3] & 0x1F != 0 {
+        return None;
+    }
+
+    let flags = header[3];
+    let mut mode = MODE_GRAPHICS | MODE_ENABLE;
+    if flags & 0x80 != 0 {
+        mode |= MODE_BW;
+    }
+    let mut color_select = header[0] >> 4;
+    if flags & 0x40 != 0 {
+        color_select |= COLOR_PALETTE;
+    }
+    if flags & 0x20 != 0 {
+        color_select |= COLOR_INTENSE;
+    }
+    Some(VideoRegisters {
+        mode,
+        color_select,
+        colorplus: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a version 5 PCX header for an image at the origin.
+    fn pcx_header(bits: u8, planes: u8, width: u16, height: u16, line_bytes: u16, palette: &[u8]) -> Vec<u8> {
+        let mut header = vec![0; PCX_HEADER_SIZE];
+        header[..4].copy_from_slice(&[0x0A, 5, 1, bits]);
+        header[8..10].copy_from_slice(&(width - 1).to_le_bytes());
+        header[10..12].copy_from_slice(&(height - 1).to_le_bytes());
+        header[16..16 + palette.len()].copy_from_slice(palette);
+        header[65] = planes;
+        header[66..68].copy_from_slice(&line_bytes.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn unpack_row_splits_most_significant_first() {
+        assert_eq!(unpack_row(&[0b1010_0000], 1, 3).collect::<Vec<_>>(), [1, 0, 1]);
+        assert_eq!(unpack_row(&[0b0001_1011], 2, 4).collect::<Vec<_>>(), [0, 1, 2, 3]);
+        assert_eq!(unpack_row(&[0x12, 0x30], 4, 3).collect::<Vec<_>>(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn cga_pcx_uses_header_registers() {
+        // Blue background, palette 1 with intensity.
+        let mut data = pcx_header(2, 1, 4, 2, 2, &[0x10, 0, 0, 0x60]);
+        // The second row is a run of two bytes, as bytes of 0xC0 and up must be.
+        data.extend_from_slice(&[0x1B, 0x00, 0xC2, 0xE4]);
+
+        let img = load_pcx(&data).unwrap();
+        assert_eq!((img.width, img.height), (4, 2));
+        assert_eq!(img.indices, [0, 1, 2, 3, 3, 2, 1, 0]);
+
+        let regs = img.registers.unwrap();
+        assert_eq!(regs.mode, MODE_GRAPHICS | MODE_ENABLE);
+        assert_eq!(regs.color_select, 0x01 | COLOR_PALETTE | COLOR_INTENSE);
+        let expected: Vec<RGBColor> = [1, 11, 13, 15].iter().map(|&c| CGA_RGB_TABLE[c]).collect();
+        assert_eq!(img.palette, expected);
+    }
+
+    #[test]
+    fn cga_pcx_burst_bit_sets_bw_mode() {
+        let mut data = pcx_header(2, 1, 4, 1, 2, &[0x00, 0, 0, 0x80]);
+        data.extend_from_slice(&[0x1B, 0x00]);
+
+        let regs = load_pcx(&data).unwrap().registers.unwrap();
+        assert_eq!(regs.mode, MODE_GRAPHICS | MODE_ENABLE | MODE_BW);
+    }
+
+    #[test]
+    fn planar_pcx_combines_planes() {
+        let palette: Vec<u8> = CGA_RGB_TABLE.iter().flat_map(|c| [c.r, c.g, c.b]).collect();
+        let mut data = pcx_header(1, 4, 8, 1, 1, &palette);
+        // Planes 0 to 3, low bit first; 0xCC and 0xF0 need runs of one.
+        data.extend_from_slice(&[0xAA, 0xC1, 0xCC, 0xC1, 0xF0, 0x00]);
+
+        let img = load_pcx(&data).unwrap();
+        assert_eq!(img.indices, [7, 6, 5, 4, 3, 2, 1, 0]);
+        assert_eq!(img.palette, CGA_RGB_TABLE);
+        assert!(img.registers.is_none());
+    }
+
+    #[test]
+    fn bottom_up_bmp_flips_rows() {
+        const PIXEL_OFFSET: u32 = 14 + 40 + 16 * 4;
+        let mut data = vec![0; PIXEL_OFFSET as usize];
+        data[..2].copy_from_slice(b"BM");
+        data[10..14].copy_from_slice(&PIXEL_OFFSET.to_le_bytes());
+        data[14..18].copy_from_slice(&40u32.to_le_bytes());
+        data[18..22].copy_from_slice(&3i32.to_le_bytes());
+        data[22..26].copy_from_slice(&2i32.to_le_bytes());
+        data[28..30].copy_from_slice(&4u16.to_le_bytes());
+        // Palette entries are stored as BGR plus a reserved byte.
+        data[58..62].copy_from_slice(&[0x10, 0x20, 0x30, 0]);
+        // Rows are padded to 4 bytes, bottom row first.
+        data.extend_from_slice(&[0x45, 0x60, 0, 0, 0x12, 0x30, 0, 0]);
+
+        let img = load_bmp(&data).unwrap().unwrap();
+        assert_eq!((img.width, img.height), (3, 2));
+        assert_eq!(img.indices, [1, 2, 3, 4, 5, 6]);
+        assert_eq!(img.palette.len(), 16);
+        assert_eq!(img.palette[1], RGBColor { r: 0x30, g: 0x20, b: 0x10 });
+    }
+}
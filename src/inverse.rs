@@ -0,0 +1,259 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    Inverse encoding
+
+    Searches for the CGA pixel data whose composite decode best matches a
+    truecolor image. Each scanline is chosen one color clock (4 hdots) at a
+    time: every pixel pattern the mode allows in that clock is decoded with
+    the Reenigne decoder in a small window around it, and the pattern with
+    the lowest error over the clock and its neighbors is kept. In modes with
+    few patterns per clock the next clock is searched jointly with it. Later
+    passes revisit each clock with both neighbors decided. The error of each chosen
+    clock can optionally be diffused to its neighbors.
+*/
+
+use std::time::Instant;
+
+use image::imageops::FilterType;
+
+use crate::composite::CGA_RGB_TABLE;
+use crate::output::save_output;
+use crate::reenigne_composite::{ReCompositeBuffers, ReCompositeContext};
+use crate::vram::{Register, VRAM_HDOTS, VRAM_LINES};
+use crate::EncodeArgs;
+
+pub const CLOCK_HDOTS: usize = 4;
+const ERROR_MARGIN: usize = 4; // Hdots scored on each side of a color clock
+const WINDOW_MARGIN: usize = 16; // Hdots decoded on each side, enough to settle the filters
+const MAX_CANDIDATES: usize = 256;
+const MAX_LOOKAHEAD: usize = 256; // Largest number of clock pairs searched jointly
+
+/// Run the encode subcommand.
+pub fn run(args: &EncodeArgs) -> Result<(), String> {
+    let mut regs = args.mode.registers();
+    if let Some(Register(value)) = args.color_reg {
+        regs.color_select = value;
+    }
+    let (pixel_hdots, colors) = regs.pixel_colors();
+    let candidates = clock_patterns(pixel_hdots, &colors);
+
+    let target = image::open(&args.input)
+        .map_err(|e| format!("Couldn't open input image '{:?}': {}", args.input, e))?
+        .resize_exact(VRAM_HDOTS, VRAM_LINES, FilterType::Lanczos3)
+        .to_rgb32f();
+    let target: Vec<[f32; 3]> = target.pixels().map(|p| p.0).collect();
+
+    let mut ctx = ReCompositeContext::new();
+    ctx.set_hue_offset(args.tint as f64);
+    ctx.update_cga16_color(regs.mode);
+
+    let bench_t = Instant::now();
+    let cga_buf = search(&mut ctx, &target, &candidates, args.passes.max(1), args.dither);
+    log::debug!("Inverse encoding took: {} ms", (Instant::now() - bench_t).as_millis());
+
+    // Write the RGBI source at the mode's pixel resolution, so it can be decoded again.
+    let src_w = VRAM_HDOTS / pixel_hdots;
+    let mut rgbi = Vec::with_capacity((src_w * VRAM_LINES * 3) as usize);
+    for idx in cga_buf.iter().step_by(pixel_hdots as usize) {
+        let c = CGA_RGB_TABLE[*idx as usize];
+        rgbi.extend_from_slice(&[c.r, c.g, c.b]);
+    }
+    let rgbi_path = format!("{}_rgbi.png", args.output);
+    image::save_buffer(&rgbi_path, &rgbi, src_w, VRAM_LINES, image::ColorType::Rgb8)
+        .map_err(|e| format!("Error writing {}: {}", rgbi_path, e))?;
+    println!("Wrote {}!", rgbi_path);
+
+    // The image doesn't record the mode, which sets the reenigne decoder's hue.
+    println!(
+        "Searched with 3D8h={:02X}h, 3D9h={:02X}h; decode with --method reenigne --mode-reg 0x{:02X} to match the preview",
+        regs.mode, regs.color_select, regs.mode
+    );
+
+    let preview = ctx.decode_image(&cga_buf, VRAM_HDOTS, VRAM_LINES);
+    save_output(&preview, &format!("{}_preview", args.output), args.format);
+
+    let err: f32 = preview
+        .pixels
+        .iter()
+        .zip(&target)
+        .map(|(a, b)| distance(a, b))
+        .sum::<f32>()
+        / (target.len() * 3) as f32;
+    println!("RMS error per channel: {:.5}", err.sqrt());
+    Ok(())
+}
+
+/// Return every 4 hdot pattern of CGA color indices a mode can produce within one color clock.
+pub fn clock_patterns(pixel_hdots: u32, colors: &[u8]) -> Vec<[u8; CLOCK_HDOTS]> {
+    let pixels = CLOCK_HDOTS / pixel_hdots as usize;
+    let count = colors.len().pow(pixels as u32).min(MAX_CANDIDATES);
+
+    (0..count)
+        .map(|mut k| {
+            let mut pattern = [0; CLOCK_HDOTS];
+            for p in 0..pixels {
+                let color = colors[k % colors.len()];
+                k /= colors.len();
+                for h in 0..pixel_hdots as usize {
+                    pattern[p * pixel_hdots as usize + h] = color;
+                }
+            }
+            pattern
+        })
+        .collect()
+}
+
+#[inline]
+fn distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    (0..3).map(|c| (a[c].clamp(0.0, 1.0) - b[c]).powi(2)).sum()
+}
+
+/// Return the candidate whose RGBI colors are closest to the target, as a starting point.
+fn nearest_pattern(candidates: &[[u8; CLOCK_HDOTS]], target: &[[f32; 3]]) -> [u8; CLOCK_HDOTS] {
+    let rgbi = |i: u8| {
+        let c = CGA_RGB_TABLE[i as usize];
+        [c.r as f32 / 255.0, c.g as f32 / 255.0, c.b as f32 / 255.0]
+    };
+    *candidates
+        .iter()
+        .min_by(|a, b| {
+            let ea: f32 = a.iter().zip(target).map(|(i, t)| distance(&rgbi(*i), t)).sum();
+            let eb: f32 = b.iter().zip(target).map(|(i, t)| distance(&rgbi(*i), t)).sum();
+            ea.total_cmp(&eb)
+        })
+        .unwrap()
+}
+
+/// Choose the pattern for every color clock of the image.
+fn search(
+    ctx: &mut ReCompositeContext,
+    target: &[[f32; 3]],
+    candidates: &[[u8; CLOCK_HDOTS]],
+    passes: u32,
+    dither: bool,
+) -> Vec<u8> {
+    let w = VRAM_HDOTS as usize;
+    let clocks = w / CLOCK_HDOTS;
+    let mut buffers = ReCompositeBuffers::new();
+
+    let mut cga_buf = vec![0u8; w * VRAM_LINES as usize];
+    for (line, t_line) in cga_buf.chunks_mut(w).zip(target.chunks(w)) {
+        for (clock, t_clock) in line.chunks_mut(CLOCK_HDOTS).zip(t_line.chunks(CLOCK_HDOTS)) {
+            clock.copy_from_slice(&nearest_pattern(candidates, t_clock));
+        }
+    }
+
+    for pass in 0..passes {
+        // Targets with diffused error added, rebuilt each pass.
+        let mut work = target.to_vec();
+
+        for y in 0..VRAM_LINES as usize {
+            let line = &mut cga_buf[y * w..(y + 1) * w];
+
+            for clock in 0..clocks {
+                let x0 = clock * CLOCK_HDOTS;
+                // Search the following clock jointly when that is affordable, so the choice
+                // here doesn't rely on a neighbor that hasn't been decided yet.
+                let lookahead = clock + 1 < clocks && candidates.len().pow(2) <= MAX_LOOKAHEAD;
+                let span = if lookahead { 2 * CLOCK_HDOTS } else { CLOCK_HDOTS };
+
+                let win_start = x0.saturating_sub(WINDOW_MARGIN);
+                let win_end = (x0 + span + WINDOW_MARGIN).min(w);
+                let err_start = x0.saturating_sub(ERROR_MARGIN);
+                let err_end = (x0 + span + ERROR_MARGIN).min(w);
+                let cur = x0 - win_start;
+
+                let mut window = line[win_start..win_end].to_vec();
+                let mut decoded = vec![[0.0f32; 3]; window.len()];
+                let mut best = (f32::MAX, line[x0..x0 + CLOCK_HDOTS].try_into().unwrap(), [[0.0f32; 3]; CLOCK_HDOTS]);
+
+                let next_options: Vec<Option<&[u8; CLOCK_HDOTS]>> = if lookahead {
+                    candidates.iter().map(Some).collect()
+                } else {
+                    vec![None]
+                };
+
+                for pattern in candidates {
+                    window[cur..cur + CLOCK_HDOTS].copy_from_slice(pattern);
+
+                    for next in &next_options {
+                        if let Some(next) = next {
+                            window[cur + CLOCK_HDOTS..cur + span].copy_from_slice(*next);
+                        }
+                        ctx.composite_process(0, window.len(), &mut buffers, &window, &mut decoded);
+
+                        let err: f32 = (err_start..err_end)
+                            .map(|x| distance(&decoded[x - win_start], &work[y * w + x]))
+                            .sum();
+                        if err < best.0 {
+                            let mut out = [[0.0; 3]; CLOCK_HDOTS];
+                            out.copy_from_slice(&decoded[cur..cur + CLOCK_HDOTS]);
+                            best = (err, *pattern, out);
+                        }
+                    }
+                }
+
+                line[x0..x0 + CLOCK_HDOTS].copy_from_slice(&best.1);
+
+                if dither {
+                    diffuse(&mut work, y, clock, &best.2);
+                }
+            }
+        }
+        log::debug!("Inverse encoding pass {} done", pass + 1);
+    }
+
+    cga_buf
+}
+
+/// Spread the error of a decoded color clock over the neighboring clocks, Floyd-Steinberg style.
+fn diffuse(work: &mut [[f32; 3]], y: usize, clock: usize, decoded: &[[f32; 3]; CLOCK_HDOTS]) {
+    let w = VRAM_HDOTS as usize;
+    let clocks = (w / CLOCK_HDOTS) as isize;
+    let x0 = clock * CLOCK_HDOTS;
+
+    let mut err = [0.0f32; 3];
+    for (x, d) in decoded.iter().enumerate() {
+        for c in 0..3 {
+            err[c] += (work[y * w + x0 + x][c] - d[c].clamp(0.0, 1.0)) / CLOCK_HDOTS as f32;
+        }
+    }
+
+    for (dx, dy, weight) in [(1isize, 0usize, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)] {
+        let cx = clock as isize + dx;
+        let cy = y + dy;
+        if cx < 0 || cx >= clocks || cy >= VRAM_LINES as usize {
+            continue;
+        }
+        for x in 0..CLOCK_HDOTS {
+            let o = cy * w + cx as usize * CLOCK_HDOTS + x;
+            for c in 0..3 {
+                work[o][c] += err[c] * weight / 16.0;
+            }
+        }
+    }
+}
@@ -27,27 +27,57 @@
 
 #![allow(unused)]
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 use std::str::FromStr;
 
-use bytemuck;
 use bpaf::{Bpaf, Parser};
-use image;
-use image::imageops::FilterType;
+use cgmath::Vector2;
 
+mod analog;
+mod animation;
+mod calibrate;
+mod colorimetry;
 mod composite;
+mod crt;
+mod field;
+mod indexed;
+mod inverse;
 mod ntsc;
+mod output;
+mod palette;
+mod pipe;
+mod profile;
 mod reenigne_composite;
+mod rgbi;
+mod scale;
+mod vram;
+mod waveform;
 
-use ntsc::OutputType;
-use reenigne_composite::{ReCompositeContext, ReCompositeBuffers};
+use colorimetry::{Colorimetry, DecodeMatrix, Phosphor, Primaries};
+use ntsc::{CombFilter, DecodeSettings, FilterBandwidth, OutputType};
+use analog::{AnalogModel, ColorSkew};
+use animation::{save_animation, AnimationFormat};
+use crt::{CrtSettings, MaskType};
+use composite::{CompositeSignal, EncoderModel, ModelParams, MAX_SAMPLES_PER_HDOT};
+use vram::{Register, VideoRegisters, VramMode};
+use waveform::FrameTiming;
+use palette::PatternColors;
+use output::{save_output, save_signal, FloatImage, FrameSize, OutputFormat, SignalMetadata};
+use indexed::{IndexMap, IndexedImage};
+use pipe::{PipeFormat, PipeReader};
+use profile::{MonitorProfile, Profile};
+use rgbi::{MatchOptions, SourcePalette};
+use scale::{ScaleFilter, ScaleMode, Scaler};
+use reenigne_composite::{MuxTable, ReCompositeContext};
 
 #[derive (Copy, Clone, Debug, Bpaf)]
 pub enum SampleMethod {
     Fast,
     Accurate,
-    Reenigne
+    Filtered,
+    Reenigne,
+    Mono
 }
 
 impl FromStr for SampleMethod {
@@ -59,229 +89,1070 @@ impl FromStr for SampleMethod {
         match s.to_lowercase().as_str() {
             "fast" => Ok(SampleMethod::Fast),
             "accurate" => Ok(SampleMethod::Accurate),
+            "filtered" => Ok(SampleMethod::Filtered),
             "reenigne" => Ok(SampleMethod::Reenigne),
+            "mono" => Ok(SampleMethod::Mono),
             _ => Err("Bad value for validatortype".to_string()),
         }
     }
 }
 
+// Parsed once at startup, so the size of the decode arguments doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Bpaf)]
-#[bpaf(version, generate(cli_args))]
-pub struct CmdLineArgs {
+#[bpaf(options, version, generate(cli_args))]
+pub enum Command {
+    /// Fit the composite model to captures of a real monitor and write a model profile
+    #[bpaf(command("calibrate"))]
+    Calibrate(#[bpaf(external(calibrate_args))] CalibrateArgs),
+    /// Search for CGA pixel data whose composite decode matches a truecolor image
+    #[bpaf(command("encode"))]
+    Encode(#[bpaf(external(encode_args))] EncodeArgs),
+    /// Export the artifact colors of a graphics mode as .gpl, .pal, PNG and JSON palettes
+    #[bpaf(command("palette"))]
+    Palette(#[bpaf(external(palette_args))] PaletteArgs),
+    Decode(#[bpaf(external(cmd_line_args))] CmdLineArgs),
+}
+
+#[derive(Debug, Bpaf)]
+pub struct CalibrateArgs {
+    /// RGBI source image; give one for each --capture, in the same order
+    #[bpaf(long, argument("PATH"))]
+    pub source: Vec<PathBuf>,
+
+    /// Capture of a source image on a real monitor, cropped to the active area
+    #[bpaf(long, argument("PATH"))]
+    pub capture: Vec<PathBuf>,
+
+    /// Decoder to fit through. The reenigne and mono methods fit intensity levels, the others fit
+    /// the composite encoder levels
     #[bpaf(long)]
-    pub input: PathBuf,
+    pub method: SampleMethod,
 
     #[bpaf(long, short)]
-    pub hue: f32,
+    pub hue: Option<f32>,
 
     #[bpaf(long, short)]
-    pub sat: f32,
+    pub sat: Option<f32>,
 
     #[bpaf(long, short)]
+    pub luma: Option<f32>,
+
+    /// Hue offset in degrees, applied on top of --hue or the reenigne reference color
+    #[bpaf(long, argument("DEGREES"))]
+    pub tint: Option<f32>,
+
+    /// Mode register (3D8h) value the captures were taken in, for the reenigne and mono
+    /// methods. Defaults to the mode a source records, otherwise 80 column text mode
+    #[bpaf(long, argument("VALUE"))]
+    pub mode_reg: Option<Register>,
+
+    /// Card and monitor profile to decode through, as for decoding. Options given on the
+    /// command line override the profile
+    #[bpaf(long, argument("NAME|PATH"))]
+    pub profile: Option<String>,
+
+    /// Model profile to start from, instead of the profile's encoder levels
+    #[bpaf(long, argument("PATH"))]
+    pub model: Option<PathBuf>,
+
+    /// Compare every Nth pixel in each direction
+    #[bpaf(long, argument("N"), fallback(4))]
+    pub stride: u32,
+
+    /// Maximum optimizer iterations
+    #[bpaf(long, argument("N"), fallback(50))]
+    pub iterations: u32,
+
+    /// Model profile to write
+    #[bpaf(long, argument("PATH"), fallback(PathBuf::from("model.json")))]
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Bpaf)]
+pub struct EncodeArgs {
+    /// Truecolor image to match; it is resized to 640x200
+    #[bpaf(long, argument("PATH"))]
+    pub input: PathBuf,
+
+    /// Graphics mode to encode for: cga640, cga320, colorplus320 or colorplus640
+    #[bpaf(long, argument("MODE"), fallback(VramMode::Cga640))]
+    pub mode: VramMode,
+
+    /// Color select register (3D9h) value, choosing the foreground color or palette
+    #[bpaf(long, argument("VALUE"))]
+    pub color_reg: Option<Register>,
+
+    /// Hue offset in degrees for the reenigne decoder
+    #[bpaf(long, argument("DEGREES"), fallback(0.0))]
+    pub tint: f32,
+
+    /// Search passes; later passes refine each color clock with both neighbors chosen
+    #[bpaf(long, argument("N"), fallback(2))]
+    pub passes: u32,
+
+    /// Diffuse the error of each color clock to its neighbors
+    #[bpaf(long)]
+    pub dither: bool,
+
+    /// Output file name stem; writes STEM_rgbi.png and the STEM_preview image
+    #[bpaf(long, argument("STEM"), fallback("encoded".to_string()))]
+    pub output: String,
+
+    /// Preview file format: png8, png16, exr or pfm
+    #[bpaf(long, argument("FORMAT"), fallback(OutputFormat::Png8))]
+    pub format: OutputFormat,
+}
+
+#[derive(Debug, Bpaf)]
+pub struct PaletteArgs {
+    /// Graphics mode: cga640, cga320, colorplus320 or colorplus640
+    #[bpaf(long, argument("MODE"), fallback(VramMode::Cga640))]
+    pub mode: VramMode,
+
+    /// Color select register (3D9h) value, choosing the foreground color or palette
+    #[bpaf(long, argument("VALUE"))]
+    pub color_reg: Option<Register>,
+
+    /// Decoder to produce the colors with
+    #[bpaf(long, fallback(SampleMethod::Reenigne))]
+    pub method: SampleMethod,
+
+    #[bpaf(long, short, fallback(ntsc::HUE))]
+    pub hue: f32,
+
+    #[bpaf(long, short, fallback(ntsc::SAT))]
+    pub sat: f32,
+
+    #[bpaf(long, short, fallback(ntsc::BRI))]
     pub luma: f32,
 
+    /// Hue offset in degrees
+    #[bpaf(long, argument("DEGREES"), fallback(0.0))]
+    pub tint: f32,
+
+    /// Output file name stem; writes STEM.gpl, STEM.pal, STEM.png and STEM.json
+    #[bpaf(long, argument("STEM"), fallback("palette".to_string()))]
+    pub output: String,
+}
+
+#[derive(Debug, Bpaf)]
+pub struct CmdLineArgs {
+    /// Input image, VRAM dump, animation or frame sequence. Not used with --pipe
+    #[bpaf(long, argument("PATH"), fallback(PathBuf::from("-")))]
+    pub input: PathBuf,
+
+    /// Read raw frames from standard input and write decoded raw RGBA frames to standard
+    /// output: rgba for 4 bytes per pixel, or index for one CGA color index byte per pixel
+    #[bpaf(long, argument("FORMAT"))]
+    pub pipe: Option<PipeFormat>,
+
+    /// Size of --pipe input frames; 320 pixel wide frames are doubled like images
+    #[bpaf(long, argument("WxH"), fallback(FrameSize { width: 640, height: 200 }))]
+    pub pipe_size: FrameSize,
+
+    /// RGB values of the input's 16 colors: cga, cga-dark-yellow, vga, vga-dark-yellow or a file
+    /// of 16 RRGGBB lines. Detected from the image by default
+    #[bpaf(long, argument("NAME|PATH"))]
+    pub source_palette: Option<String>,
+
+    /// Report input pixels further than this RGB distance from every palette color
+    #[bpaf(long, argument("DISTANCE"), fallback(rgbi::DEFAULT_TOLERANCE))]
+    pub match_tolerance: f32,
+
+    /// How the indices of a palette indexed PNG, GIF, BMP or PCX input become CGA colors: auto,
+    /// direct, color, or a comma separated list of the CGA color for each index
+    #[bpaf(long, argument("MAP"), fallback(IndexMap::Auto))]
+    pub index_map: IndexMap,
+
+    /// Output container for animated inputs: apng, gif, or sequence for numbered images in --format
+    #[bpaf(long, argument("FORMAT"), fallback(AnimationFormat::Apng))]
+    pub animation: AnimationFormat,
+
+    /// Frame rate of image sequence inputs, given as a directory or a pattern such as
+    /// frame_%04d.png. Defaults to the CGA's refresh rate
+    #[bpaf(long, argument("FPS"), fallback(animation::CGA_FRAME_RATE))]
+    pub fps: f64,
+
+    /// Decode the whole 262 line field: the active lines with the overscan border above and
+    /// below them and the vertical blanking interval, starting at vertical sync
     #[bpaf(long)]
-    pub method: SampleMethod
+    pub field: bool,
+
+    /// Show each scanline as two output lines, the second darkened by DARKNESS (0-1), like the
+    /// gaps between the lines of 240p video on a CRT
+    #[bpaf(long, argument("DARKNESS"))]
+    pub line_gaps: Option<f32>,
+
+    /// Scale the output: none, double to repeat every line, or a size such as 640x480 or
+    /// 1280x960 to fit the picture at a 4:3 aspect ratio
+    #[bpaf(long, argument("SCALE"), fallback(ScaleMode::None))]
+    pub scale: ScaleMode,
+
+    /// Filter for --scale sizes: nearest, bilinear or lanczos
+    #[bpaf(long, argument("FILTER"), fallback(ScaleFilter::Lanczos))]
+    pub scale_filter: ScaleFilter,
+
+    /// Present the output as it would look on a CRT, with scanlines, a phosphor mask, beam
+    /// spread, bloom and curvature. Implied by any of the options below
+    #[bpaf(long)]
+    pub crt: bool,
+
+    /// Darkening between scanlines for --crt, 0-1. Needs at least two output lines per
+    /// scanline, see --scale
+    #[bpaf(long, argument("STRENGTH"))]
+    pub scanlines: Option<f32>,
+
+    /// Phosphor mask for --crt: none, aperture, shadow or slot
+    #[bpaf(long, argument("TYPE"))]
+    pub mask: Option<MaskType>,
+
+    /// How much the phosphor mask dims the other colors, 0-1
+    #[bpaf(long, argument("STRENGTH"))]
+    pub mask_strength: Option<f32>,
+
+    /// Width of one phosphor triad in output pixels
+    #[bpaf(long, argument("PIXELS"))]
+    pub mask_pitch: Option<u32>,
+
+    /// Horizontal spread of a full brightness beam for --crt, in decoded pixels
+    #[bpaf(long, argument("PIXELS"))]
+    pub beam_spread: Option<f32>,
+
+    /// Strength of the glow around bright areas for --crt
+    #[bpaf(long, argument("AMOUNT"))]
+    pub bloom: Option<f32>,
+
+    /// Barrel distortion for --crt, as how far the corners bow outward
+    #[bpaf(long, argument("AMOUNT"))]
+    pub curvature: Option<f32>,
+
+    /// Read the input as a raw dump of video memory in the given graphics mode: cga320, cga640,
+    /// colorplus320 (320x200x16) or colorplus640 (640x200x4)
+    #[bpaf(long, argument("MODE"))]
+    pub vram_mode: Option<VramMode>,
+
+    /// Mode register (3D8h) value for --vram-mode, overriding the mode's default. Also sets
+    /// the mode other inputs are decoded in by the reenigne and mono methods
+    #[bpaf(long, argument("VALUE"))]
+    pub mode_reg: Option<Register>,
+
+    /// Color select register (3D9h) value for --vram-mode, overriding the mode's default
+    #[bpaf(long, argument("VALUE"))]
+    pub color_reg: Option<Register>,
+
+    /// Plantronics ColorPlus control register (3DDh) value for --vram-mode, overriding the
+    /// mode's default
+    #[bpaf(long, argument("VALUE"))]
+    pub colorplus_reg: Option<Register>,
+
+    #[bpaf(long, short)]
+    pub hue: Option<f32>,
+
+    #[bpaf(long, short)]
+    pub sat: Option<f32>,
+
+    #[bpaf(long, short)]
+    pub luma: Option<f32>,
+
+    #[bpaf(long)]
+    pub method: SampleMethod,
+
+    /// Card and monitor profile: a TOML or JSON file, or one of ibm5153-old-cga, 1702-new-cga
+    /// or dosbox. Options given on the command line override the profile
+    #[bpaf(long, argument("NAME|PATH"))]
+    pub profile: Option<String>,
+
+    /// Luma bandwidth in MHz for the filtered method
+    #[bpaf(long, argument("MHZ"))]
+    pub luma_bw: Option<f32>,
+
+    /// I bandwidth in MHz for the filtered method
+    #[bpaf(long, argument("MHZ"))]
+    pub i_bw: Option<f32>,
+
+    /// Q bandwidth in MHz for the filtered method
+    #[bpaf(long, argument("MHZ"))]
+    pub q_bw: Option<f32>,
+
+    /// Comb filter for the filtered method: 1line (notch), 2line or 3line
+    #[bpaf(long, argument("LINES"))]
+    pub comb: Option<CombFilter>,
+
+    /// Chroma decoding matrix: fcc-yiq, or smpte-c-yuv which gives the same result
+    #[bpaf(long, argument("MATRIX"))]
+    pub matrix: Option<DecodeMatrix>,
+
+    /// Convert from the given display primaries to sRGB: none, ntsc1953 or smpte-c
+    #[bpaf(long, argument("PRIMARIES"))]
+    pub primaries: Option<Primaries>,
+
+    /// Phosphor of the monochrome monitor for the mono method: white, green (P31) or amber
+    #[bpaf(long, argument("PHOSPHOR"), fallback(Phosphor::White))]
+    pub phosphor: Phosphor,
+
+    /// Write linear-light output instead of sRGB encoded output
+    #[bpaf(long)]
+    pub linear: bool,
+
+    /// Output file format: png8, png16, exr or pfm
+    #[bpaf(long, argument("FORMAT"), fallback(OutputFormat::Png8))]
+    pub format: OutputFormat,
+
+    /// Scale color clock aligned edges of the composite signal (0.8 models edge slew)
+    #[bpaf(long, argument("FACTOR"))]
+    pub edge_response: Option<f32>,
+
+    /// Load composite model parameters from a profile written by the calibrate subcommand
+    #[bpaf(long, argument("PATH"))]
+    pub model: Option<PathBuf>,
+
+    /// Load a chroma multiplexer table for the reenigne method from a JSON file with a 256 entry
+    /// chroma_multiplexer array and optional intensity levels
+    #[bpaf(long, argument("PATH"))]
+    pub mux_table: Option<PathBuf>,
+
+    /// Write the multiplexer table and intensity levels in use to a JSON file
+    #[bpaf(long, argument("PATH"))]
+    pub export_mux_table: Option<PathBuf>,
+
+    /// Composite samples per hdot (1-16); 2 samples half-hdots as the original encoder did
+    #[bpaf(long, argument("N"), fallback(2))]
+    pub samples_per_hdot: u32,
+
+    /// Encode with the analog RC model of the output stage instead of ideal waveforms
+    #[bpaf(long)]
+    pub analog: bool,
+
+    /// Rise time constant of the chroma output in ns, for --analog
+    #[bpaf(long, argument("NS"), fallback(AnalogModel::default().chroma_rise))]
+    pub chroma_rise: f32,
+
+    /// Fall time constant of the chroma output in ns, for --analog
+    #[bpaf(long, argument("NS"), fallback(AnalogModel::default().chroma_fall))]
+    pub chroma_fall: f32,
+
+    /// Rise time constant of the intensity output in ns, for --analog
+    #[bpaf(long, argument("NS"), fallback(AnalogModel::default().intensity_rise))]
+    pub intensity_rise: f32,
+
+    /// Fall time constant of the intensity output in ns, for --analog
+    #[bpaf(long, argument("NS"), fallback(AnalogModel::default().intensity_fall))]
+    pub intensity_fall: f32,
+
+    /// Delay of each of the 8 color clocks in ns as a comma separated list, for --analog
+    #[bpaf(long, argument("NS,..."), fallback(AnalogModel::default().skew))]
+    pub color_skew: ColorSkew,
+
+    /// Export the composite signal as f32 samples (.npy or raw) with a .json metadata file
+    #[bpaf(long, argument("PATH"))]
+    pub export_signal: Option<PathBuf>,
+
+    /// Export complete scanlines with sync, blanking, color burst and a vertical interval
+    #[bpaf(long)]
+    pub full_frame: bool,
+
+    /// CGA color index (0-15) of the overscan border in full frame exports and with --field
+    #[bpaf(long, argument("COLOR"), fallback(0))]
+    pub border: u8,
+
+    /// Decode each 4 hdot color clock as one pixel of the effective 160x200 16 color mode, using
+    /// the flat field color of its pattern
+    #[bpaf(long)]
+    pub patterns: bool,
+
+    /// With --patterns, write 160 pixel wide output instead of repeating each pixel 4 times
+    #[bpaf(long)]
+    pub narrow: bool,
+
+    /// Derive the hue of the NTSC decoders from the color burst instead of --hue
+    #[bpaf(long)]
+    pub burst_lock: bool,
+
+    /// Lock to the burst of a full frame composite signal (.npy or raw f32, with the .json
+    /// metadata --export-signal writes), such as a capture, instead of the frame built from the input
+    #[bpaf(long, argument("PATH"))]
+    pub burst_signal: Option<PathBuf>,
+
+    /// Hue offset in degrees, applied on top of --hue, the burst or the reenigne reference color
+    #[bpaf(long, argument("DEGREES"))]
+    pub tint: Option<f32>,
+}
+
+/// Load an RGBI image and convert it to CGA color indices, one per hdot. 320 pixel wide
+/// images are doubled horizontally and vertically. Also returns the registers of the CGA mode
+/// the image was drawn in, for files that record it.
+pub fn load_cga_image(
+    path: &Path,
+    matching: &MatchOptions,
+) -> Result<(Vec<u8>, u32, u32, Option<VideoRegisters>), String> {
+    let indexed = indexed::load_indexed(path)?;
+
+    let (img_w, img_h, bytes_in) = match &indexed {
+        Some(img) => (img.width, img.height, img.to_rgba()),
+        None => {
+            let img = image::open(path).map_err(|e| format!("Couldn't open input image '{:?}': {}", path, e))?;
+            (img.width(), img.height(), img.to_rgba8().into_raw())
+        }
+    };
+
+    let cga_buf = convert_to_cga(&bytes_in, img_w, indexed.as_ref(), matching)?;
+    let (cga_buf, img_w, img_h) = double_low_res(cga_buf, img_w, img_h);
+    Ok((cga_buf, img_w, img_h, indexed.and_then(|img| img.registers)))
+}
+
+/// Convert RGBA pixels to CGA color indices. The indices of an indexed image are used where
+/// they can be trusted.
+fn convert_to_cga(
+    bytes_in: &[u8],
+    img_w: u32,
+    indexed: Option<&IndexedImage>,
+    matching: &MatchOptions,
+) -> Result<Vec<u8>, String> {
+    if img_w != 320 && img_w != 640 {
+        return Err(format!("Unsupported image width: {}", img_w));
+    }
+
+    let palette = match &matching.palette {
+        Some(name) => SourcePalette::load(name)?,
+        None => rgbi::detect_palette(bytes_in),
+    };
+    log::debug!("Matching colors against the '{}' palette", palette.name);
+
+    // Use the indices of an indexed image where they can be trusted, otherwise convert RGB
+    // source image to indexed color.
+    let cga_buf = match (indexed, &matching.index_map) {
+        (Some(img), IndexMap::Direct) => img.direct()?,
+        (Some(img), IndexMap::Table(table)) => img.remap(table)?,
+        (Some(img @ IndexedImage { registers: Some(regs), .. }), IndexMap::Auto) => img.remap(&regs.pixel_colors().1)?,
+        (Some(img), IndexMap::Auto) if img.matches_palette(&palette, matching.tolerance) => {
+            log::debug!("Using the image's palette indices directly");
+            img.direct()?
+        }
+        _ => {
+            if indexed.is_some() && matching.index_map == IndexMap::Auto {
+                println!("Image palette is not in CGA order; matching its colors instead");
+            }
+            let (cga_buf, report) = rgbi::match_image(bytes_in, img_w, &palette, matching.tolerance);
+            report.print();
+            cga_buf
+        }
+    };
+    Ok(cga_buf)
+}
+
+/// Add any line gaps to a decoded image, scale it for output and run it through the CRT stage,
+/// if there is one.
+fn present(img: FloatImage, line_gaps: Option<f32>, scaler: &Scaler, crt: Option<&CrtSettings>) -> FloatImage {
+    let img = match line_gaps {
+        Some(darkness) => field::line_gaps(img, darkness),
+        None => img,
+    };
+    let source = FrameSize { width: img.width, height: img.height };
+    let (_, picture) = scaler.placement(img.width, img.height);
+    let scaled = scaler.apply(img);
+    match crt {
+        Some(crt) => crt.apply(scaled, source, picture),
+        None => scaled,
+    }
+}
+
+/// Double 320 pixel wide images to 640 before processing.
+pub fn double_low_res(cga_buf: Vec<u8>, img_w: u32, img_h: u32) -> (Vec<u8>, u32, u32) {
+    if img_w != 320 {
+        return (cga_buf, img_w, img_h);
+    }
+    let doubled: Vec<u8> = cga_buf
+        .chunks(img_w as usize)
+        .flat_map(|line| {
+            let line: Vec<u8> = line.iter().flat_map(|&c| [c, c]).collect();
+            [line.clone(), line]
+        })
+        .flatten()
+        .collect();
+    (doubled, img_w * 2, img_h * 2)
+}
+
+impl CmdLineArgs {
+    /// Settings of the CRT stage, if it is enabled, from the command line, then the monitor
+    /// profile, then the defaults.
+    fn crt_settings(&self, monitor: &MonitorProfile) -> Option<CrtSettings> {
+        let enabled = self.crt
+            || self.scanlines.is_some()
+            || self.mask.is_some()
+            || self.mask_strength.is_some()
+            || self.mask_pitch.is_some()
+            || self.beam_spread.is_some()
+            || self.bloom.is_some()
+            || self.curvature.is_some();
+        if !enabled {
+            return None;
+        }
+
+        let defaults = CrtSettings::default();
+        Some(CrtSettings {
+            scanlines: self.scanlines.or(monitor.scanlines).unwrap_or(defaults.scanlines),
+            mask: self.mask.or(monitor.mask).unwrap_or(defaults.mask),
+            mask_strength: self.mask_strength.or(monitor.mask_strength).unwrap_or(defaults.mask_strength),
+            mask_pitch: self.mask_pitch.or(monitor.mask_pitch).unwrap_or(defaults.mask_pitch),
+            beam_spread: self.beam_spread.or(monitor.beam_spread).unwrap_or(defaults.beam_spread),
+            bloom: self.bloom.or(monitor.bloom).unwrap_or(defaults.bloom),
+            curvature: self.curvature.or(monitor.curvature).unwrap_or(defaults.curvature),
+        })
+    }
+
+    fn match_options(&self) -> MatchOptions {
+        MatchOptions {
+            palette: self.source_palette.clone(),
+            tolerance: self.match_tolerance,
+            index_map: self.index_map.clone(),
+        }
+    }
+}
+
+/// Frames of CGA color indices, one per hdot, and how long each is shown in seconds.
+struct InputFrames {
+    frames: Vec<Vec<u8>>,
+    delays: Vec<f64>,
+    width: u32,
+    height: u32,
+    /// Registers of the graphics mode the input was displayed in, if it records one.
+    registers: Option<VideoRegisters>,
+}
+
+/// Load the input as a list of frames. Still images and VRAM dumps give a single frame.
+fn load_frames(
+    shell_args: &CmdLineArgs,
+    vram_mode: Option<VramMode>,
+    pipe: Option<&mut PipeReader>,
+) -> Result<InputFrames, String> {
+    let matching = shell_args.match_options();
+
+    // Only the first frame is read here; the rest are streamed as they arrive.
+    if let Some(reader) = pipe {
+        let (cga_buf, img_w, img_h) = reader.next_frame()?.ok_or("No frames on standard input")?;
+        return Ok(InputFrames {
+            frames: vec![cga_buf],
+            delays: vec![0.0],
+            width: img_w,
+            height: img_h,
+            registers: None,
+        });
+    }
+
+    if let Some(mode) = vram_mode {
+        let (cga_buf, img_w, img_h, regs) = load_vram_image(shell_args, mode)?;
+        return Ok(InputFrames {
+            frames: vec![cga_buf],
+            delays: vec![0.0],
+            width: img_w,
+            height: img_h,
+            registers: Some(regs),
+        });
+    }
+
+    if let Some(paths) = animation::sequence_paths(&shell_args.input)? {
+        let mut frames = Vec::with_capacity(paths.len());
+        let mut size = None;
+        let mut registers = None;
+        for path in &paths {
+            let (cga_buf, img_w, img_h, regs) = load_cga_image(path, &matching)?;
+            registers = registers.or(regs);
+            if *size.get_or_insert((img_w, img_h)) != (img_w, img_h) {
+                return Err(format!("Frame '{:?}' is a different size from the first frame", path));
+            }
+            frames.push(cga_buf);
+        }
+        let (img_w, img_h) = size.unwrap();
+        println!("Loaded {} frames", frames.len());
+        return Ok(InputFrames {
+            frames,
+            delays: vec![1.0 / shell_args.fps; paths.len()],
+            width: img_w,
+            height: img_h,
+            registers,
+        });
+    }
+
+    if let Some(anim) = animation::load_animation(&shell_args.input)? {
+        let mut frames = Vec::with_capacity(anim.frames.len());
+        let mut delays = Vec::with_capacity(anim.frames.len());
+        let mut size = (anim.width, anim.height);
+        for frame in &anim.frames {
+            let cga_buf = convert_to_cga(&frame.rgba, anim.width, None, &matching)?;
+            let (cga_buf, img_w, img_h) = double_low_res(cga_buf, anim.width, anim.height);
+            size = (img_w, img_h);
+            frames.push(cga_buf);
+            delays.push(frame.delay);
+        }
+        println!("Loaded {} frames", frames.len());
+        return Ok(InputFrames {
+            frames,
+            delays,
+            width: size.0,
+            height: size.1,
+            registers: None,
+        });
+    }
+
+    let (cga_buf, img_w, img_h, registers) = load_cga_image(&shell_args.input, &matching)?;
+    Ok(InputFrames {
+        frames: vec![cga_buf],
+        delays: vec![0.0],
+        width: img_w,
+        height: img_h,
+        registers,
+    })
 }
 
 fn main() {
     env_logger::init();
 
-    let shell_args: CmdLineArgs = cli_args().run();
+    match cli_args().run() {
+        Command::Calibrate(args) => {
+            if let Err(e) = calibrate::run(&args) {
+                eprintln!("Calibration failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Encode(args) => {
+            if let Err(e) = inverse::run(&args) {
+                eprintln!("Encoding failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Palette(args) => {
+            if let Err(e) = palette::run(&args) {
+                eprintln!("Palette export failed: {}", e);
+                std::process::exit(1);
+            }
+        }
+        Command::Decode(args) => decode(args),
+    }
+}
+
+/// Generate the RGBI stream for a VRAM dump in the given mode, applying any register overrides.
+/// Returns the registers in effect along with the stream.
+fn load_vram_image(
+    shell_args: &CmdLineArgs,
+    mode: VramMode,
+) -> Result<(Vec<u8>, u32, u32, VideoRegisters), String> {
+    let mut regs = mode.registers();
+    if let Some(Register(value)) = shell_args.mode_reg {
+        regs.mode = value;
+    }
+    if let Some(Register(value)) = shell_args.color_reg {
+        regs.color_select = value;
+    }
+    if let Some(Register(value)) = shell_args.colorplus_reg {
+        regs.colorplus = value;
+    }
+
+    let vram = vram::load_vram(&shell_args.input)?;
+    let (cga_buf, img_w, img_h) = vram::decode_vram(&vram, &regs)?;
+    Ok((cga_buf, img_w, img_h, regs))
+}
+
+/// Set up a reenigne context for a profile's card and monitor, with the given matrix, tint in
+/// degrees and intensity levels. The mode is left for the caller to set.
+pub fn reenigne_context(
+    profile: &Profile,
+    matrix: DecodeMatrix,
+    tint: f32,
+    intensity: [f64; 4],
+) -> Result<ReCompositeContext, String> {
+    let monitor = &profile.monitor;
+    let mut ctx = ReCompositeContext::new();
+    ctx.set_decode_matrix(matrix);
+    ctx.set_hue_offset(tint as f64);
+    ctx.set_intensity(intensity);
+    ctx.set_new_cga(profile.card.new_cga);
+    if let Some(table) = &profile.card.chroma_multiplexer {
+        ctx.set_chroma_multiplexer(table)
+            .map_err(|e| format!("Bad multiplexer table in profile: {}", e))?;
+    }
+    ctx.set_controls(
+        monitor.brightness.unwrap_or(0.0),
+        monitor.contrast.unwrap_or(100.0),
+        monitor.saturation.unwrap_or(100.0),
+        monitor.sharpness.unwrap_or(0.0),
+    );
+    Ok(ctx)
+}
 
-    let img = image::open(shell_args.input.clone()).unwrap_or_else(|e| {
-        eprintln!("Couldn't open input image '{:?}': {}", shell_args.input, e);
+/// Demodulate the color burst of a full frame signal with the given timing.
+fn frame_burst(frame: &CompositeSignal, timing: &FrameTiming) -> Vector2<f32> {
+    ntsc::measure_burst(
+        &frame.samples,
+        frame.width,
+        frame.height,
+        frame.samples_per_hdot,
+        timing.burst_start..(timing.burst_start + timing.burst_samples),
+        timing.vsync_line..(timing.vsync_line + timing.vsync_lines),
+    )
+}
+
+/// Load a full frame signal for --burst-signal and demodulate its burst. The signal's metadata
+/// must give its frame timing, and sample 0 must start a color clock so that the decoders'
+/// subcarrier phase applies.
+fn load_burst_signal(path: &Path) -> Result<Vector2<f32>, String> {
+    let (signal, metadata) = output::load_signal(path)?;
+    let timing = metadata
+        .frame
+        .ok_or_else(|| format!("Signal '{:?}' has no frame timing; export it with --full-frame", path))?;
+    if metadata.hdot_phase != 0.0 {
+        return Err(format!("Signal '{:?}' doesn't start on a color clock", path));
+    }
+    Ok(frame_burst(&signal, &timing))
+}
+
+fn decode(shell_args: CmdLineArgs) {
+    // Standard output carries the decoded frames when piping, so nothing else may be printed.
+    let piping = shell_args.pipe.is_some();
+    if piping && (shell_args.vram_mode.is_some() || shell_args.export_signal.is_some() || shell_args.export_mux_table.is_some())
+    {
+        eprintln!("--vram-mode, --export-signal and --export-mux-table can't be used with --pipe");
         std::process::exit(1);
-    });
+    }
+    if !piping && shell_args.input.as_os_str() == "-" {
+        eprintln!("No input given; use --input, or --pipe to read from standard input");
+        std::process::exit(1);
+    }
 
-    let mut img_w = img.width();
-    let mut img_h = img.height();
+    if shell_args.field && shell_args.full_frame {
+        eprintln!("--field can't be used with --full-frame, which builds its own field");
+        std::process::exit(1);
+    }
+    let field_border = shell_args.field.then_some(shell_args.border % 16);
 
-    //let rgba8_img = img.to_rgba8();
+    if !(1..=MAX_SAMPLES_PER_HDOT).contains(&shell_args.samples_per_hdot) {
+        eprintln!("--samples-per-hdot must be between 1 and {}", MAX_SAMPLES_PER_HDOT);
+        std::process::exit(1);
+    }
 
-    //println!("Loaded ({} x {}) image of length {}.", img_w, img_h, bytes_in.len());
+    let mut pipe_reader = shell_args.pipe.map(|format| {
+        PipeReader::new(format, shell_args.pipe_size, shell_args.match_options(), field_border).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+    });
 
-    let mut out_width_factor = 1;
-    let rgba8_img;
-    let mut bytes_in;
-    let mut rgba_out;
+    // BSAVE images don't record the mode they were saved in.
+    let vram_mode = shell_args.vram_mode.or_else(|| {
+        (!piping && vram::is_bsave(&shell_args.input)).then(|| {
+            println!("Input is a BSAVE image; decoding as cga320, use --vram-mode to choose another mode");
+            VramMode::Cga320
+        })
+    });
 
-    match img_w {
-        320 => {
-            // Resize to 640 before processing.
-            rgba8_img = img
-                .resize(img_w * 2, img_h * 2, FilterType::Nearest)
-                .to_rgba8();
+    let InputFrames {
+        mut frames,
+        delays,
+        width: img_w,
+        height: mut img_h,
+        registers,
+    } = load_frames(&shell_args, vram_mode, pipe_reader.as_mut()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
 
-            img_w = rgba8_img.width();
-            img_h = rgba8_img.height();
+    // The pipe reader assembles its own fields as each frame arrives.
+    if let Some(border) = field_border.filter(|_| !piping) {
+        frames = frames
+            .iter()
+            .map(|f| field::assemble(f, img_w, img_h, border))
+            .collect::<Result<_, _>>()
+            .unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+        img_h = waveform::FRAME_LINES;
+    }
+    let animated = frames.len() > 1;
+    let scaler = Scaler::new(shell_args.scale, shell_args.scale_filter);
+    let cga_buf = &frames[0];
 
-            bytes_in = rgba8_img.into_raw();
-            rgba_out = vec![0; bytes_in.len()];
-        }
-        640 => {
-            rgba8_img = img.to_rgba8();
-            bytes_in = rgba8_img.into_raw();
+    let profile = match &shell_args.profile {
+        Some(name) => Profile::load(name).unwrap_or_else(|e| {
+            eprintln!("Couldn't load profile '{}': {}", name, e);
+            std::process::exit(1);
+        }),
+        None => Profile::default(),
+    };
+    let monitor = &profile.monitor;
+    if let Some(name) = profile.name.as_ref().filter(|_| !piping) {
+        println!("Using profile: {}", name);
+    }
 
-            rgba_out = vec![0; bytes_in.len()];
+    // Command line options take precedence over the profile, which takes precedence over the
+    // built-in defaults.
+    let matrix = shell_args.matrix.or(monitor.matrix).unwrap_or(DecodeMatrix::FccYiq);
+    let primaries = shell_args.primaries.or(monitor.primaries).unwrap_or(Primaries::None);
+    let tint = shell_args.tint.or(monitor.tint).unwrap_or(0.0);
+
+    let crt = shell_args.crt_settings(monitor);
+    if crt.as_ref().is_some_and(|c| c.scanlines > 0.0) {
+        let out_h = if shell_args.line_gaps.is_some() { field::gapped_height(img_h) } else { img_h };
+        let (_, picture) = scaler.placement(img_w, out_h);
+        if picture.height < field::scanlines(out_h) * 2 {
+            eprintln!("Scanlines need at least two output lines per scanline, use --scale to enlarge the output");
         }
-        _ => {
-            println!("Unsupported image width: {}", img_w);
+    }
+
+    let mut params = match &shell_args.model {
+        Some(path) => ModelParams::load(path).unwrap_or_else(|e| {
+            eprintln!("Couldn't load model profile '{:?}': {}", path, e);
             std::process::exit(1);
-        }
+        }),
+        None => profile.card.model,
+    };
+    if let Some(edge_response) = shell_args.edge_response {
+        params.edge_response = edge_response;
     }
 
-    // Do reenigne convert.
-    if let SampleMethod::Reenigne = shell_args.method {
-        
-        let mut comp_ctx = ReCompositeContext::new();
-        //comp_ctx.update_cga16_color(0b1_0110); // hires graphics
-        comp_ctx.update_cga16_color(0b0_0001); // 80 col text mode graphics
+    // Convert the indexed image to a composite signal.
+    let model = if shell_args.analog {
+        EncoderModel::Analog(AnalogModel {
+            chroma_rise: shell_args.chroma_rise,
+            chroma_fall: shell_args.chroma_fall,
+            intensity_rise: shell_args.intensity_rise,
+            intensity_fall: shell_args.intensity_fall,
+            skew: shell_args.color_skew,
+        })
+    } else {
+        EncoderModel::Digital
+    };
+    let spd = shell_args.samples_per_hdot;
+    let signal = CompositeSignal::encode(cga_buf, img_w, img_h, spd, &model, &params);
 
-        comp_ctx.print();
+    // Build complete scanlines around the active signal if anything needs sync or burst.
+    let burst_lock = shell_args.burst_lock || shell_args.burst_signal.is_some();
+    let frame = (shell_args.full_frame || (burst_lock && shell_args.burst_signal.is_none()))
+        .then(|| waveform::encode_frame(&signal, shell_args.border % 16, &model, &params));
 
-        // Create buffers.
-        let mut comp_buf = ReCompositeBuffers::new();
+    if let Some(path) = &shell_args.export_signal {
+        let result = if let (true, Some(frame)) = (shell_args.full_frame, &frame) {
+            save_signal(frame, &SignalMetadata::for_frame(frame), path)
+        } else {
+            save_signal(&signal, &SignalMetadata::new(&signal, 0.0), path)
+        };
+        match result {
+            Ok(_) => println!("Wrote {}!", path.display()),
+            Err(e) => {
+                println!("Error writing signal file: {}", e)
+            }
+        }
+    }
 
-        // Convert RGB source image to indexed color.
-        let mut cga_buf: Vec<u8> = vec![0; (img_w * img_h) as usize];
-        composite::convert_rgb_to_cga_idx(&mut bytes_in, &mut cga_buf, img_w, img_h);
+    let colorimetry = Colorimetry::new(matrix, primaries, shell_args.linear);
 
-        // Convert every row of source image.
+    // Set up the reenigne context. Its multiplexer table can be exported whatever the method.
+    let mut comp_ctx = reenigne_context(&profile, matrix, tint, params.intensity).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if let Some(path) = &shell_args.mux_table {
+        MuxTable::load(path)
+            .and_then(|table| comp_ctx.set_mux_table(&table))
+            .unwrap_or_else(|e| {
+                eprintln!("Couldn't load multiplexer table '{:?}': {}", path, e);
+                std::process::exit(1);
+            });
+    }
+    if let Some(path) = &shell_args.export_mux_table {
+        match comp_ctx.mux_table().save(path) {
+            Ok(_) => println!("Wrote {}!", path.display()),
+            Err(e) => {
+                println!("Error writing multiplexer table: {}", e)
+            }
+        }
+    }
 
-        let rgba_out32: &mut [u32] = bytemuck::cast_slice_mut(&mut rgba_out);
+    // Do reenigne convert. The mono method uses the same composite table, without chroma.
+    if let SampleMethod::Reenigne | SampleMethod::Mono = shell_args.method {
+        let mono = matches!(shell_args.method, SampleMethod::Mono);
+        comp_ctx.set_monochrome(mono);
+        let colorimetry = if mono {
+            colorimetry.with_phosphor(shell_args.phosphor)
+        } else {
+            colorimetry
+        };
 
-        rgba_out32.fill(0xFFFFFFFF);
+        // --mode-reg overrides the mode recorded by the input; inputs that don't record one are
+        // decoded as 80 column text mode.
+        let mode = shell_args
+            .mode_reg
+            .map(|Register(value)| value)
+            .or(registers.map(|regs| regs.mode))
+            .unwrap_or(vram::MODE_TEXT_80);
+        comp_ctx.update_cga16_color(mode);
 
-        // Bench reenigne composite
-        let bench_t = Instant::now();
+        if !piping {
+            comp_ctx.print();
+        }
 
-        for y in 0..img_h {
+        let stem = if mono { "./out_mono" } else { "./out_reenigne" };
 
-            let yo = (y * img_w) as usize;
-            let in_slice = &mut cga_buf[yo..(yo + img_w as usize)];
-            let out_slice = &mut rgba_out32[yo..(yo + img_w as usize)];
+        // Convert every row of a frame.
+        let mut patterns = PatternColors::default();
+        let mut decode_frame = |cga_buf: &[u8]| {
+            let mut rgb_out = if shell_args.patterns {
+                patterns.decode(cga_buf, img_w, img_h, shell_args.narrow, |field, w, h| {
+                    comp_ctx.decode_image(field, w, h)
+                })
+            } else {
+                comp_ctx.decode_image(cga_buf, img_w, img_h)
+            };
+            rgb_out.map_in_place(|rgb| colorimetry.apply(rgb));
+            present(rgb_out, shell_args.line_gaps, &scaler, crt.as_ref())
+        };
 
-            comp_ctx.composite_process(0, img_w as usize, &mut comp_buf, in_slice, out_slice);
+        if let Some(reader) = pipe_reader.as_mut() {
+            if let Err(e) = pipe::stream(reader, cga_buf, decode_frame) {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
         }
 
+        // Bench reenigne composite
+        let bench_t = Instant::now();
+
+        let decoded: Vec<FloatImage> = frames.iter().map(|f| decode_frame(f)).collect();
+
         let us = (Instant::now() - bench_t).as_micros();
         let ms = us as f64 / 1000.0;
         log::debug!("reenigne composite took: {} ms", ms);
 
+        if animated {
+            save_animation(&decoded, &delays, stem, shell_args.animation, shell_args.format);
+        } else {
+            save_output(&decoded[0], stem, shell_args.format);
+        }
+
+        return;
+    }
+
+    // Non-reenigne methods
+
+    if !piping {
         match image::save_buffer(
-            "./out_reenigne.png",
-            &rgba_out,
-            img_w,
-            img_h,
-            image::ColorType::Rgba8,
+            "./out_composite.png",
+            &signal.to_l8(),
+            signal.width,
+            signal.height,
+            image::ColorType::L8,
         ) {
-            Ok(_) => println!("Wrote out_reenigne.png!"),
+            Ok(_) => println!("Wrote out_composite.png!"),
             Err(e) => {
                 println!("Error writing output file: {}", e)
             }
-        }     
+        }
+    }
 
-        return;
+    let mut hue = shell_args.hue.or(monitor.hue).unwrap_or(ntsc::HUE) + tint.to_radians();
+
+    if burst_lock {
+        let burst = match (&shell_args.burst_signal, &frame) {
+            (Some(path), _) => load_burst_signal(path).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }),
+            (None, Some(frame)) => frame_burst(frame, &FrameTiming::new(frame.samples_per_hdot)),
+            (None, None) => unreachable!("the frame is built for --burst-lock"),
+        };
+        match ntsc::burst_locked_hue(burst, tint) {
+            Some(h) => {
+                if !piping {
+                    println!("Locked to color burst, hue: {:.3}", h);
+                }
+                hue = h;
+            }
+            None if piping => {}
+            None => println!("No color burst found, using --hue"),
+        }
     }
 
-    // Non-reenigne methods
+    let settings = DecodeSettings {
+        hue,
+        sat: shell_args.sat.or(monitor.sat).unwrap_or(ntsc::SAT),
+        luma: shell_args.luma.or(monitor.luma).unwrap_or(ntsc::BRI),
+        bandwidth: FilterBandwidth {
+            luma: shell_args.luma_bw.or(monitor.luma_bw).unwrap_or(ntsc::LUMA_BW),
+            i: shell_args.i_bw.or(monitor.i_bw).unwrap_or(ntsc::I_BW),
+            q: shell_args.q_bw.or(monitor.q_bw).unwrap_or(ntsc::Q_BW),
+        },
+        comb: shell_args.comb.or(monitor.comb).unwrap_or(CombFilter::Notch),
+        colorimetry,
+    };
+
+    // Decode a frame to RGB, as flat field patterns or through its composite signal.
+    let mut patterns = PatternColors::default();
+    let mut decode_frame = |cga_buf: &[u8]| {
+        let rgb_out = if shell_args.patterns {
+            patterns.decode(cga_buf, img_w, img_h, shell_args.narrow, |field, w, h| {
+                let field_signal = CompositeSignal::encode(field, w, h, spd, &model, &params);
+                let mut field_out = FloatImage::new(w, h);
+                ntsc::process(&field_signal, &mut field_out, shell_args.method, &settings, OutputType::Rgb);
+                field_out
+            })
+        } else {
+            let frame_signal = CompositeSignal::encode(cga_buf, img_w, img_h, spd, &model, &params);
+            let mut rgb_out = FloatImage::new(img_w, img_h);
+            ntsc::process(&frame_signal, &mut rgb_out, shell_args.method, &settings, OutputType::Rgb);
+            rgb_out
+        };
+        present(rgb_out, shell_args.line_gaps, &scaler, crt.as_ref())
+    };
 
-    // First, convert the input image to a composite signal. The resulting image will be grayscale (/4) of twice the horizontal resolution (*2)
-    let mut composite_out = vec![0; bytes_in.len() / 2];
-    let pre_composite_t = Instant::now();
-
-    //composite::process_cga_composite(&mut bytes_in, &mut composite_out, img_w, img_h);
-    composite::process_cga_composite_int(&mut bytes_in, &mut composite_out, img_w, img_h);
-
-    let composite_time = (Instant::now() - pre_composite_t).as_millis();
-    log::debug!("Composite conversion took: {} ms.", composite_time);
-
-    match image::save_buffer(
-        "./out_composite.png",
-        &composite_out,
-        img_w * 2,
-        img_h,
-        image::ColorType::L8,
-    ) {
-        Ok(_) => println!("Wrote out_composite.png!"),
-        Err(e) => {
-            println!("Error writing output file: {}", e)
+    if let Some(reader) = pipe_reader.as_mut() {
+        if let Err(e) = pipe::stream(reader, cga_buf, decode_frame) {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
+        return;
+    }
+
+    if animated {
+        let decoded: Vec<FloatImage> = frames.iter().map(|f| decode_frame(f)).collect();
+        save_animation(&decoded, &delays, "./out", shell_args.animation, shell_args.format);
+        return;
+    }
+
+    if shell_args.patterns {
+        save_output(&decode_frame(cga_buf), "./out", shell_args.format);
+        return;
     }
 
+    let mut rgb_out = FloatImage::new(img_w, img_h);
+
     ntsc::process(
-        &composite_out,
-        &mut rgba_out,
-        img_w * 2,
-        img_h,
-        shell_args.hue,
-        shell_args.sat,
-        shell_args.luma,
+        &signal,
+        &mut rgb_out,
         shell_args.method,
+        &settings,
         OutputType::Rgb,
     );
 
-    match image::save_buffer(
-        "./out.png",
-        &rgba_out,
-        img_w,
-        img_h,
-        image::ColorType::Rgba8,
-    ) {
-        Ok(_) => println!("Wrote out.png!"),
-        Err(e) => {
-            println!("Error writing output file: {}", e)
-        }
-    }
+    save_output(&present(rgb_out.clone(), shell_args.line_gaps, &scaler, crt.as_ref()), "./out", shell_args.format);
 
     ntsc::process(
-        &composite_out,
-        &mut rgba_out,
-        img_w * 2,
-        img_h,
-        shell_args.hue,
-        shell_args.sat,
-        shell_args.luma,        
+        &signal,
+        &mut rgb_out,
         shell_args.method,
+        &settings,
         OutputType::Luma,
     );
 
-    match image::save_buffer(
-        "./out_luma.png",
-        &rgba_out,
-        img_w,
-        img_h,
-        image::ColorType::Rgba8,
-    ) {
-        Ok(_) => println!("Wrote out_luma.png!"),
-        Err(e) => {
-            println!("Error writing output file: {}", e)
-        }
-    }
+    save_output(&present(rgb_out.clone(), shell_args.line_gaps, &scaler, crt.as_ref()), "./out_luma", shell_args.format);
 
     ntsc::process(
-        &composite_out,
-        &mut rgba_out,
-        img_w * 2,
-        img_h,
-        shell_args.hue,
-        shell_args.sat,
-        shell_args.luma,
+        &signal,
+        &mut rgb_out,
         shell_args.method,
+        &settings,
         OutputType::Chroma,
     );
 
-    match image::save_buffer(
-        "./out_chroma.png",
-        &rgba_out,
-        img_w,
-        img_h,
-        image::ColorType::Rgba8,
-    ) {
-        Ok(_) => println!("Wrote out_chroma.png!"),
-        Err(e) => {
-            println!("Error writing output file: {}", e)
-        }
-    }
+    save_output(&present(rgb_out.clone(), shell_args.line_gaps, &scaler, crt.as_ref()), "./out_chroma", shell_args.format);
 }
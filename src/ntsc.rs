@@ -32,46 +32,121 @@
 
 #![allow(unused)]
 
+use std::f32::consts::{PI, TAU};
+use std::str::FromStr;
 use std::time::Instant;
 
 use cgmath::{Matrix3, Vector2, Vector3};
 
+use crate::colorimetry::Colorimetry;
+use crate::composite::CompositeSignal;
+use crate::output::FloatImage;
 use crate::SampleMethod;
 
-#[rustfmt::skip]
-static YIQ2RGB: Matrix3<f32> = Matrix3::new(
-    1.000, 1.000, 1.000, 
-    0.956, -0.272, -1.106, 
-    0.621, -0.647, 1.703,
-);
-
-const CCYCLE: i32 = 8;
-const CCYCLE_HALF: i32 = CCYCLE / 2;
-
 const N: usize = 15; //  Filter Width
 const N2: usize = N * 2;
 const M: usize = N / 2; //  Filter Middle
 const M2: usize = N2 / 2;
 
+// The sampling windows of the fast and accurate decoders are fixed by the CGA's sample rate
+// rather than by a monitor, so profiles don't set them; a profile's bandwidths apply to the
+// filtered decoder only.
 const FC: f32 = 0.25; //  Frequency Cutoff
 const SCF: f32 = 0.25; //  Subcarrier Frequency
 
-const FC2: f32 = 0.125; //  Frequency Cutoff
-const SCF2: f32 = 0.125; //  Subcarrier Frequency
+const FC2: f32 = 0.125; //  Frequency Cutoff, at 2 samples per hdot
+
+const HDOT_RATE: f32 = 14.318181; // Hdot clock in MHz; the composite sample rate is a multiple of this
+const NOTCH_BW: f32 = 1.0; // Width of the luma trap around the subcarrier in MHz
+const MIN_TAPS: usize = 7;
+const MAX_TAPS: usize = 4095; // Enough for the Q filter at 16 samples per hdot
+
+pub const LUMA_BW: f32 = 4.2; // Luma bandwidth in MHz
+pub const I_BW: f32 = 1.3; // I bandwidth in MHz
+pub const Q_BW: f32 = 0.4; // Q bandwidth in MHz
 
-const PI: f32 = 3.1415926;
-const TAU: f32 = 6.2831853;
+pub const SAT: f32 = 1.5;
+pub const HUE: f32 = 2.0; // 0.5 Looks good for KQ, Speedway
+pub const BRI: f32 = 1.0;
 
-const SAT: f32 = 1.5;
-const HUE: f32 = 2.0; // 0.5 Looks good for KQ, Speedway
-const BRI: f32 = 1.0;
+const BURST_IQ_ANGLE: f32 = -57.0; // Angle of a standard NTSC burst (-U) on the I/Q axes, in degrees
 
+#[derive(Copy, Clone)]
 pub enum OutputType {
     Rgb,
     Luma,
     Chroma,
 }
 
+/// Bandwidths, in MHz, of the independent Y, I and Q filter paths used by the filtered decoder.
+#[derive(Copy, Clone, Debug)]
+pub struct FilterBandwidth {
+    pub luma: f32,
+    pub i: f32,
+    pub q: f32,
+}
+
+impl Default for FilterBandwidth {
+    fn default() -> Self {
+        Self {
+            luma: LUMA_BW,
+            i: I_BW,
+            q: Q_BW,
+        }
+    }
+}
+
+/// Vertical comb filter applied by the filtered decoder before Y/C separation.
+///
+/// Broadcast NTSC inverts the subcarrier phase on every line, which a comb filter relies on to
+/// cancel chroma out of luma. The CGA's 912-hdot line holds a whole number of subcarrier cycles,
+/// so its phase repeats on every line instead, and a comb filter set behaves very differently
+/// from a notch filter set on the same signal.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CombFilter {
+    /// 1-line: no comb filter; luma is separated with a notch at the subcarrier.
+    Notch,
+    /// 2-line: one line delay; averages and differences the current and previous lines.
+    TwoLine,
+    /// 3-line: two line delays; uses the previous, current and next lines.
+    ThreeLine,
+}
+
+impl FromStr for CombFilter {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "1line" | "1" | "none" | "notch" => Ok(CombFilter::Notch),
+            "2line" | "2" => Ok(CombFilter::TwoLine),
+            "3line" | "3" => Ok(CombFilter::ThreeLine),
+            _ => Err("Bad value for comb filter, expected 1line, 2line or 3line".to_string()),
+        }
+    }
+}
+
+/// Picture controls and filter settings shared by the NTSC decoders.
+pub struct DecodeSettings {
+    pub hue: f32,
+    pub sat: f32,
+    pub luma: f32,
+    pub bandwidth: FilterBandwidth,
+    pub comb: CombFilter,
+    pub colorimetry: Colorimetry,
+}
+
+impl Default for DecodeSettings {
+    fn default() -> Self {
+        Self {
+            hue: HUE,
+            sat: SAT,
+            luma: BRI,
+            bandwidth: FilterBandwidth::default(),
+            comb: CombFilter::Notch,
+            colorimetry: Colorimetry::default(),
+        }
+    }
+}
+
 pub struct NTSCWeights {
     weights: [f32; N],
 }
@@ -92,26 +167,213 @@ impl NTSCWeights {
     }
 }
 
+/// Sampling weights for the accurate decoder. The window is N2 samples wide at 2 samples per
+/// hdot and is scaled with the sample rate so it always covers the same span of signal.
 pub struct NTSCWeightsWide {
-    weights: [f32; N2],
+    weights: Vec<f32>,
 }
 impl NTSCWeightsWide {
-    pub fn new() -> Self {
-        let mut s = Self { weights: [0.0; N2] };
+    pub fn new(samples_per_hdot: u32) -> Self {
+        let taps = (N2 as u32 * samples_per_hdot / 2).max(2) as usize;
+        let fc = FC2 * 2.0 / samples_per_hdot as f32;
+        let mut s = Self { weights: vec![0.0; taps] };
 
         let mut sum: f32 = 0.0;
-        for n in 0..N2 {
-            s.weights[n] = hann(n as f32, N2 as f32) * sinc(FC2 * (n as i32 - M2 as i32) as f32);
+        for n in 0..taps {
+            s.weights[n] = hann(n as f32, taps as f32) * sinc(fc * (n as i32 - (taps / 2) as i32) as f32);
             sum += s.weights[n];
         }
         // Normalize sampling weights
-        for n in 0..N2 {
-            s.weights[n] /= sum;
+        for w in s.weights.iter_mut() {
+            *w /= sum;
         }
         s
     }
 }
 
+/// FIR kernels for the filtered decoder. Luma passes through a notch at the subcarrier
+/// followed by a lowpass, while I and Q are demodulated and then lowpassed separately.
+/// When a comb filter has already separated luma, only the luma lowpass is applied.
+pub struct NTSCFilters {
+    luma: Vec<f32>,
+    luma_comb: Vec<f32>,
+    i: Vec<f32>,
+    q: Vec<f32>,
+}
+impl NTSCFilters {
+    pub fn new(bw: &FilterBandwidth, samples_per_hdot: u32) -> Self {
+        let sample_rate = HDOT_RATE * samples_per_hdot as f32;
+        let subcarrier = 1.0 / color_cycle(samples_per_hdot) as f32;
+        let notch = notch_kernel(subcarrier, NOTCH_BW / 2.0 / sample_rate);
+        let luma_lp = lowpass_kernel(bw.luma / sample_rate);
+
+        Self {
+            luma: convolve_kernels(&notch, &luma_lp),
+            luma_comb: luma_lp,
+            i: lowpass_kernel(bw.i / sample_rate),
+            q: lowpass_kernel(bw.q / sample_rate),
+        }
+    }
+}
+
+/// Return the number of samples in one cycle of the color subcarrier (4 hdots).
+#[inline]
+pub fn color_cycle(samples_per_hdot: u32) -> i32 {
+    4 * samples_per_hdot as i32
+}
+
+/// Return the offset, in samples, added to a sample's position to find its subcarrier phase.
+/// Each sample averages the signal over its span, so its phase is taken at its center, less
+/// the quarter hdot the decoders were originally tuned with at 2 samples per hdot.
+#[inline]
+pub fn phase_offset(samples_per_hdot: u32) -> f32 {
+    0.5 - samples_per_hdot as f32 / 4.0
+}
+
+/// Build cosine and sine tables of the subcarrier reference for one color cycle.
+fn carrier_tables(samples_per_hdot: u32) -> (Vec<f32>, Vec<f32>) {
+    let cycle = color_cycle(samples_per_hdot);
+    let offset = phase_offset(samples_per_hdot);
+    (0..cycle)
+        .map(|x| {
+            let phase: f32 = (x as f32 + offset) * TAU / cycle as f32;
+            (phase.cos(), phase.sin())
+        })
+        .unzip()
+}
+
+/// Build a Hann-windowed sinc lowpass kernel normalized to unity gain at DC.
+/// The cutoff `fc` is given in cycles per sample; narrower filters get more taps.
+pub fn lowpass_kernel(fc: f32) -> Vec<f32> {
+    let taps = ((2.0 / fc).ceil() as usize).clamp(MIN_TAPS, MAX_TAPS) | 1;
+    let m = (taps / 2) as i32;
+
+    let mut kernel: Vec<f32> = (0..taps)
+        .map(|n| hann(n as f32, taps as f32) * sinc(2.0 * fc * (n as i32 - m) as f32))
+        .collect();
+
+    // Normalize sampling weights
+    let sum: f32 = kernel.iter().sum();
+    for w in kernel.iter_mut() {
+        *w /= sum;
+    }
+    kernel
+}
+
+/// Build a notch kernel rejecting a band of half-width `half_bw` around `center`, both given
+/// in cycles per sample. This is an identity kernel minus a bandpass centered on `center`.
+pub fn notch_kernel(center: f32, half_bw: f32) -> Vec<f32> {
+    let mut kernel = lowpass_kernel(half_bw);
+    let m = (kernel.len() / 2) as i32;
+
+    for (n, w) in kernel.iter_mut().enumerate() {
+        *w = -*w * 2.0 * (TAU * center * (n as i32 - m) as f32).cos();
+    }
+    kernel[m as usize] += 1.0;
+    kernel
+}
+
+/// Convolve two kernels together, producing a single kernel of length a + b - 1.
+pub fn convolve_kernels(a: &[f32], b: &[f32]) -> Vec<f32> {
+    let mut kernel = vec![0.0; a.len() + b.len() - 1];
+
+    for (i, wa) in a.iter().enumerate() {
+        for (j, wb) in b.iter().enumerate() {
+            kernel[i + j] += wa * wb;
+        }
+    }
+    kernel
+}
+
+/// Apply an FIR kernel centered on sample `s` of a line of signal, clamping at the line edges.
+/// If a carrier table is given, each sample is first multiplied by the subcarrier reference
+/// at its (unclamped) position, demodulating it before filtering.
+#[inline]
+pub fn fir_at(line: &[f32], kernel: &[f32], s: i32, carrier: Option<&[f32]>) -> f32 {
+    let m = (kernel.len() / 2) as i32;
+    let last = line.len() as i32 - 1;
+    let mut acc = 0.0;
+
+    for (n, w) in kernel.iter().enumerate() {
+        let pos = s + n as i32 - m;
+        let mut signal = line[pos.clamp(0, last) as usize];
+        if let Some(carrier) = carrier {
+            signal *= carrier[pos.rem_euclid(carrier.len() as i32) as usize];
+        }
+        acc += signal * w;
+    }
+    acc
+}
+
+/// Separate a line of composite signal into luma and chroma using a vertical comb filter over
+/// the previous, current and next lines. With `CombFilter::Notch` the signal is passed
+/// through unseparated to both outputs, leaving separation to the horizontal filters.
+pub fn comb_filter(
+    prev: &[f32],
+    cur: &[f32],
+    next: &[f32],
+    mode: CombFilter,
+    luma_out: &mut [f32],
+    chroma_out: &mut [f32],
+) {
+    for x in 0..cur.len() {
+        let chroma = match mode {
+            CombFilter::Notch => {
+                luma_out[x] = cur[x];
+                chroma_out[x] = cur[x];
+                continue;
+            }
+            CombFilter::TwoLine => (cur[x] - prev[x]) * 0.5,
+            CombFilter::ThreeLine => (cur[x] * 2.0 - prev[x] - next[x]) * 0.25,
+        };
+        luma_out[x] = cur[x] - chroma;
+        chroma_out[x] = chroma;
+    }
+}
+
+/// Demodulate the color burst of every line of a full frame, using the same subcarrier phase
+/// as the decoders, and return the averaged (I, Q) burst vector. `burst` is the range of samples
+/// holding the burst and `skip_lines` the range of lines without one.
+pub fn measure_burst(
+    frame: &[f32],
+    line_len: u32,
+    lines: u32,
+    samples_per_hdot: u32,
+    burst: std::ops::Range<u32>,
+    skip_lines: std::ops::Range<u32>,
+) -> Vector2<f32> {
+    let (cos_table, sin_table) = carrier_tables(samples_per_hdot);
+    let mut iq = Vector2::new(0.0, 0.0);
+    let mut count = 0;
+
+    for y in (0..lines).filter(|y| !skip_lines.contains(y)) {
+        let line = &frame[(y * line_len) as usize..((y + 1) * line_len) as usize];
+        for s in burst.clone() {
+            let c = s as usize % cos_table.len();
+            iq += Vector2::new(cos_table[c], sin_table[c]) * line[s as usize];
+            count += 1;
+        }
+    }
+
+    if count > 0 {
+        iq / count as f32
+    } else {
+        iq
+    }
+}
+
+/// Return the hue setting that rotates a measured burst onto the axis a monitor expects it on,
+/// plus a tint offset in degrees. Returns None if there is no burst to lock to.
+pub fn burst_locked_hue(burst_iq: Vector2<f32>, tint: f32) -> Option<f32> {
+    if burst_iq.x.hypot(burst_iq.y) < 1.0e-3 {
+        return None;
+    }
+    // adjust() rotates chroma by -hue, so the hue that moves the burst onto its axis is the
+    // burst's own angle minus that of the axis.
+    let burst_angle = burst_iq.y.atan2(burst_iq.x);
+    Some(burst_angle - BURST_IQ_ANGLE.to_radians() + tint.to_radians())
+}
+
 // Adjusts a YIQ color by hue, saturation and brightness factors
 pub fn adjust(yiq: Vector3<f32>, h: f32, s: f32, b: f32) -> Vector3<f32> {
     #[rustfmt::skip]
@@ -125,7 +387,7 @@ pub fn adjust(yiq: Vector3<f32>, h: f32, s: f32, b: f32) -> Vector3<f32> {
 }
 
 pub fn hann(n: f32, nh: f32) -> f32 {
-    return 0.5 * (1.0 - ((TAU * n) / (nh - 1.0)).cos());
+    0.5 * (1.0 - ((TAU * n) / (nh - 1.0)).cos())
 }
 
 pub fn sinc(x: f32) -> f32 {
@@ -156,7 +418,7 @@ pub fn sample_rgb_norm(img_in: &[u8], img_w: u32, img_h: u32, pos: Vector2<f32>)
 
     let io = (y * img_w as i32 * 4 + x * 4) as usize;
     Vector3 {
-        x: (img_in[io + 0] as f32 / 255.0),
+        x: (img_in[io] as f32 / 255.0),
         y: (img_in[io + 1] as f32 / 255.0),
         z: (img_in[io + 2] as f32 / 255.0),
     }
@@ -186,7 +448,7 @@ pub fn sample_rgb_xy(
 
     let io = (y * img_w as i32 * 4 + x * 4) as usize;
     Vector3 {
-        x: (img_in[io + 0] as f32 / 255.0),
+        x: (img_in[io] as f32 / 255.0),
         y: (img_in[io + 1] as f32 / 255.0),
         z: (img_in[io + 2] as f32 / 255.0),
     }
@@ -194,7 +456,7 @@ pub fn sample_rgb_xy(
 
 #[inline]
 /// Return the grayscale pixel at x, y, clamped at image dimensions
-pub fn sample_gy_xy(img_in: &[u8], img_w: u32, img_h: u32, mut x: i32, mut y: i32) -> f32 {
+pub fn sample_gy_xy(img_in: &[f32], img_w: u32, img_h: u32, mut x: i32, mut y: i32) -> f32 {
     if x < 0 {
         x = 0;
     }
@@ -210,7 +472,7 @@ pub fn sample_gy_xy(img_in: &[u8], img_w: u32, img_h: u32, mut x: i32, mut y: i3
 
     let io = (y * img_w as i32 + x) as usize;
 
-    img_in[io] as f32 / 255.0
+    img_in[io]
 }
 
 #[inline]
@@ -224,21 +486,28 @@ pub fn to_u8_clamped(f: f32) -> u8 {
     }
 }
 
+/// Write a decoded pixel to the output image according to the requested output type.
+#[inline]
+fn put_pixel(img_out: &mut FloatImage, dst_o: usize, yiq: Vector3<f32>, rgb: Vector3<f32>, output_type: OutputType) {
+    match output_type {
+        OutputType::Rgb => img_out.put(dst_o, rgb),
+        OutputType::Luma => img_out.put(dst_o, Vector3::new(yiq.x, yiq.x, yiq.x)),
+        OutputType::Chroma => img_out.put(dst_o, Vector3::new(40.0 * yiq.y + 0.5, 40.0 * yiq.z + 0.5, 0.0)),
+    }
+}
+
+/// Decode a composite signal into `img_out`, one pixel per hdot.
 pub fn process(
-    img_in: &[u8],
-    img_out: &mut [u8],
-    img_w: u32,
-    img_h: u32,
-    hue: f32,
-    sat: f32,
-    luma: f32,
+    signal: &CompositeSignal,
+    img_out: &mut FloatImage,
     method: SampleMethod,
+    settings: &DecodeSettings,
     otype: OutputType,
 ) {
     //let weights = NTSCWeights::new();
 
     let pre_weight_t = Instant::now();
-    let weights_w = NTSCWeightsWide::new();
+    let weights_w = NTSCWeightsWide::new(signal.samples_per_hdot);
     let weight_time = (Instant::now() - pre_weight_t).as_millis();
 
     log::debug!("Weight calculation took: {} ms", weight_time);
@@ -247,15 +516,17 @@ pub fn process(
 
     match method {
         SampleMethod::Fast => {
-            artifact_colors_fast(img_in, img_out, img_w, img_h, hue, sat, luma, otype);
+            artifact_colors_fast(signal, img_out, settings, otype);
         }
         SampleMethod::Accurate => {
-            artifact_colors(
-                img_in, img_out, img_w, img_h, hue, sat, luma, &weights_w, otype,
-            );
+            artifact_colors(signal, img_out, settings, &weights_w, otype);
+        }
+        SampleMethod::Filtered => {
+            let filters = NTSCFilters::new(&settings.bandwidth, signal.samples_per_hdot);
+            artifact_colors_filtered(signal, img_out, settings, &filters, otype);
         }
-        SampleMethod::Reenigne => {
-            println!("unimplemented");
+        SampleMethod::Reenigne | SampleMethod::Mono => {
+            unreachable!("reenigne and mono are decoded by ReCompositeContext")
         }
     }
 
@@ -275,11 +546,11 @@ pub fn sample_luma(img_in: &mut [u8], img_out: &mut [u8], img_w: u32, img_h: u32
                 let mut rgb = sample_rgb_xy(img_in, img_w, img_h, x as i32 + n, y as i32);
                 yiq += rgb;
             }
-            yiq = yiq / 4.0;
+            yiq /= 4.0;
 
             let so = (y * img_w * 4 + x * 4) as usize;
 
-            img_out[so + 0] = to_u8_clamped(yiq.x * 255.0);
+            img_out[so] = to_u8_clamped(yiq.x * 255.0);
             img_out[so + 1] = to_u8_clamped(yiq.y * 255.0);
             img_out[so + 2] = to_u8_clamped(yiq.z * 255.0);
             img_out[so + 3] = 255;
@@ -288,22 +559,22 @@ pub fn sample_luma(img_in: &mut [u8], img_out: &mut [u8], img_w: u32, img_h: u32
 }
 
 pub fn artifact_colors_fast(
-    img_in: &[u8],
-    img_out: &mut [u8],
-    img_w: u32,
-    img_h: u32,
-    hue: f32,
-    sat: f32,
-    luma: f32,
+    signal: &CompositeSignal,
+    img_out: &mut FloatImage,
+    settings: &DecodeSettings,
     output_type: OutputType,
 ) {
-    let mut sync_table: [(f32, f32, f32); 1280 + CCYCLE as usize] =
-        [(0.0, 0.0, 0.0); 1280 + CCYCLE as usize];
+    let (img_in, img_w, img_h) = (signal.samples.as_slice(), signal.width, signal.height);
+    let spd = signal.samples_per_hdot;
+    let yiq2rgb = settings.colorimetry.matrix.yiq_to_rgb();
+    let cycle = color_cycle(spd);
+    let cycle_half = cycle / 2;
+    let mut sync_table: Vec<(f32, f32, f32)> = vec![(0.0, 0.0, 0.0); (img_w as i32 + cycle) as usize];
 
     let pre_sync_t = Instant::now();
     // Precalculate sync
-    for x in 0..(1280 + CCYCLE) {
-        let phase: f32 = ((x - CCYCLE_HALF) as f32) * TAU / 8.0;
+    for x in 0..(img_w as i32 + cycle) {
+        let phase: f32 = ((x - cycle_half) as f32 + phase_offset(spd)) * TAU / cycle as f32;
         sync_table[x as usize] = (phase, phase.cos(), phase.sin());
     }
     let sync_time = (Instant::now() - pre_sync_t).as_millis();
@@ -312,13 +583,13 @@ pub fn artifact_colors_fast(
     let mut dst_o = 0;
 
     for y in 0..img_h {
-        for x in 0..(img_w / 2) {
+        for x in 0..(img_w / spd) {
             let mut yiq: Vector3<f32> = Vector3::new(0.0, 0.0, 0.0);
 
-            for n in -CCYCLE_HALF..CCYCLE_HALF {
-                let signal = sample_gy_xy(img_in, img_w, img_h, (x * 2) as i32 + n, y as i32);
+            for n in -cycle_half..cycle_half {
+                let signal = sample_gy_xy(img_in, img_w, img_h, (x * spd) as i32 + n, y as i32);
 
-                let sti = ((x * 2) as i32 + n as i32 + CCYCLE_HALF) as usize;
+                let sti = ((x * spd) as i32 + n + cycle_half) as usize;
                 let signal_i = signal * sync_table[sti].1;
                 let signal_q = signal * sync_table[sti].2;
 
@@ -327,51 +598,35 @@ pub fn artifact_colors_fast(
                 yiq.y += signal_i;
                 yiq.z += signal_q;
             }
-            yiq = yiq / CCYCLE as f32;
+            yiq /= cycle as f32;
 
-            let adjust_yiq = adjust(yiq, hue, sat, luma);
-            let rgb = YIQ2RGB * adjust_yiq;
+            let adjust_yiq = adjust(yiq, settings.hue, settings.sat, settings.luma);
+            let rgb = settings.colorimetry.apply(yiq2rgb * adjust_yiq);
 
             //let dst_o = (y * (img_w / 2) * 4 + (x / 2) * 4) as usize;
-            match output_type {
-                OutputType::Rgb => {
-                    img_out[dst_o + 0] = to_u8_clamped(rgb.x * 255.0);
-                    img_out[dst_o + 1] = to_u8_clamped(rgb.y * 255.0);
-                    img_out[dst_o + 2] = to_u8_clamped(rgb.z * 255.0);
-                }
-                OutputType::Luma => {
-                    img_out[dst_o + 0] = to_u8_clamped(yiq.x * 255.0);
-                    img_out[dst_o + 1] = to_u8_clamped(yiq.x * 255.0);
-                    img_out[dst_o + 2] = to_u8_clamped(yiq.x * 255.0);
-                }
-                OutputType::Chroma => {
-                    img_out[dst_o + 0] = to_u8_clamped((40.0 * yiq.y + 0.5) * 255.0);
-                    img_out[dst_o + 1] = to_u8_clamped((40.0 * yiq.z + 0.5) * 255.0);
-                    img_out[dst_o + 2] = 0;
-                }
-            }
-            img_out[dst_o + 3] = 255;
+            put_pixel(img_out, dst_o, yiq, rgb, output_type);
 
-            dst_o += 4;
+            dst_o += 1;
         }
     }
 }
 
 pub fn artifact_colors(
-    img_in: &[u8],
-    img_out: &mut [u8],
-    img_w: u32,
-    img_h: u32,
-    hue: f32,
-    sat: f32,
-    luma: f32,
+    signal: &CompositeSignal,
+    img_out: &mut FloatImage,
+    settings: &DecodeSettings,
     weights: &NTSCWeightsWide,
     output_type: OutputType,
 ) {
+    let (img_in, img_w, img_h) = (signal.samples.as_slice(), signal.width, signal.height);
+    let spd = signal.samples_per_hdot;
+    let yiq2rgb = settings.colorimetry.matrix.yiq_to_rgb();
     let iq: Vector3<f32> = Vector3::new(0.0, 0.0, 0.0);
+    let taps = weights.weights.len();
+    let subcarrier = 1.0 / color_cycle(spd) as f32;
 
     for y in 0..img_h {
-        for x in 0..(img_w / 2) {
+        for x in 0..(img_w / spd) {
             // Convert x, y coords to normalized form
             let uv: Vector2<f32> = Vector2 {
                 x: x as f32 / img_w as f32,
@@ -383,16 +638,19 @@ pub fn artifact_colors(
                 y: 0.0,
                 z: 0.0,
             };
-            for n in 0..N2 {
+            for n in 0..taps {
                 // Position is calculated as (x + sample step - half filter width), this splits the sampling window across
                 // the sampled pixel position
                 let pos: Vector2<f32> = uv
                     + Vector2 {
-                        x: uv.x + (n as i32 - M2 as i32) as f32 / (img_w as f32),
+                        x: uv.x * (spd - 1) as f32 + (n as i32 - (taps / 2) as i32) as f32 / (img_w as f32),
                         y: 0.0,
                     };
 
-                let phase: f32 = TAU * (SCF2 * (img_w as f32) * pos.x);
+                // Samples are read from one position behind `pos`, a half-hdot lag at 2 samples per
+                // hdot that the hue was tuned around. Keep the same lag in hdots at other rates.
+                let lag = spd as f32 / 2.0 - 1.0;
+                let phase: f32 = TAU * subcarrier * ((img_w as f32) * pos.x + lag + phase_offset(spd));
 
                 let mut signal = sample_gy_xy(
                     img_in,
@@ -406,29 +664,119 @@ pub fn artifact_colors(
                 yiq += Vector3::new(1.0, phase.cos(), phase.sin()) * signal * weights.weights[n];
             }
 
-            let adjust_yiq = adjust(yiq, hue, sat, luma);
-            let rgb = YIQ2RGB * adjust_yiq;
-
-            let dst_o = (y * (img_w / 2) * 4 + (x) * 4) as usize;
-            img_out[dst_o + 3] = 255;
-
-            match output_type {
-                OutputType::Rgb => {
-                    img_out[dst_o + 0] = to_u8_clamped(rgb.x * 255.0);
-                    img_out[dst_o + 1] = to_u8_clamped(rgb.y * 255.0);
-                    img_out[dst_o + 2] = to_u8_clamped(rgb.z * 255.0);
-                }
-                OutputType::Luma => {
-                    img_out[dst_o + 0] = to_u8_clamped(yiq.x * 255.0);
-                    img_out[dst_o + 1] = to_u8_clamped(yiq.x * 255.0);
-                    img_out[dst_o + 2] = to_u8_clamped(yiq.x * 255.0);
-                }
-                OutputType::Chroma => {
-                    img_out[dst_o + 0] = to_u8_clamped((40.0 * yiq.y + 0.5) * 255.0);
-                    img_out[dst_o + 1] = to_u8_clamped((40.0 * yiq.z + 0.5) * 255.0);
-                    img_out[dst_o + 2] = 0;
-                }
+            let adjust_yiq = adjust(yiq, settings.hue, settings.sat, settings.luma);
+            let rgb = settings.colorimetry.apply(yiq2rgb * adjust_yiq);
+
+            let dst_o = (y * (img_w / spd) + x) as usize;
+            put_pixel(img_out, dst_o, yiq, rgb, output_type);
+        }
+    }
+}
+
+/// Decode artifact color with independent luma and chroma filter paths. Luma is taken through
+/// a notch at the subcarrier and a lowpass, while I and Q are demodulated and lowpassed with
+/// their own bandwidths, so fine luma detail survives while chroma smears as on a real set.
+pub fn artifact_colors_filtered(
+    signal: &CompositeSignal,
+    img_out: &mut FloatImage,
+    settings: &DecodeSettings,
+    filters: &NTSCFilters,
+    output_type: OutputType,
+) {
+    let (img_in, img_w, img_h) = (signal.samples.as_slice(), signal.width, signal.height);
+    let spd = signal.samples_per_hdot;
+    let yiq2rgb = settings.colorimetry.matrix.yiq_to_rgb();
+    let (cos_table, sin_table) = carrier_tables(spd);
+
+    let luma_kernel = match settings.comb {
+        CombFilter::Notch => &filters.luma,
+        _ => &filters.luma_comb,
+    };
+
+    // Rows of signal for the previous, current and next lines. Lines beyond the top and bottom
+    // of the image are clamped, as elsewhere.
+    let mut rows = [
+        vec![0.0; img_w as usize],
+        vec![0.0; img_w as usize],
+        vec![0.0; img_w as usize],
+    ];
+    let mut luma_line = vec![0.0; img_w as usize];
+    let mut chroma_line = vec![0.0; img_w as usize];
+    let mut dst_o = 0;
+
+    for y in 0..img_h {
+        for (r, row) in rows.iter_mut().enumerate() {
+            for (x, sample) in row.iter_mut().enumerate() {
+                *sample = sample_gy_xy(img_in, img_w, img_h, x as i32, y as i32 + r as i32 - 1);
             }
         }
+        comb_filter(
+            &rows[0],
+            &rows[1],
+            &rows[2],
+            settings.comb,
+            &mut luma_line,
+            &mut chroma_line,
+        );
+
+        for x in 0..(img_w / spd) {
+            let s = (x * spd) as i32;
+            let yiq = Vector3::new(
+                fir_at(&luma_line, luma_kernel, s, None),
+                fir_at(&chroma_line, &filters.i, s, Some(cos_table.as_slice())),
+                fir_at(&chroma_line, &filters.q, s, Some(sin_table.as_slice())),
+            );
+
+            let adjust_yiq = adjust(yiq, settings.hue, settings.sat, settings.luma);
+            let rgb = settings.colorimetry.apply(yiq2rgb * adjust_yiq);
+
+            put_pixel(img_out, dst_o, yiq, rgb, output_type);
+            dst_o += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return the gain of a symmetric kernel at frequency `f`, in cycles per sample.
+    fn response(kernel: &[f32], f: f32) -> f32 {
+        let m = (kernel.len() / 2) as i32;
+        kernel
+            .iter()
+            .enumerate()
+            .map(|(n, w)| w * (TAU * f * (n as i32 - m) as f32).cos())
+            .sum()
+    }
+
+    #[test]
+    fn lowpass_has_unity_dc_gain() {
+        for fc in [0.01, 0.05, 0.2, 0.45] {
+            assert!((response(&lowpass_kernel(fc), 0.0) - 1.0).abs() < 1.0e-5);
+        }
+    }
+
+    #[test]
+    fn notch_rejects_subcarrier() {
+        for spd in [1, 2, 4] {
+            let sample_rate = HDOT_RATE * spd as f32;
+            let subcarrier = 1.0 / color_cycle(spd) as f32;
+            let notch = notch_kernel(subcarrier, NOTCH_BW / 2.0 / sample_rate);
+            assert!(response(&notch, subcarrier).abs() < 0.01);
+            assert!((response(&notch, 0.0) - 1.0).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn comb_cancels_chroma_on_identical_lines() {
+        // A subcarrier at 4 samples per cycle over a luma level, the same on every line.
+        let line: Vec<f32> = (0..32).map(|x| 0.5 + 0.2 * (TAU * x as f32 / 4.0).cos()).collect();
+        for mode in [CombFilter::TwoLine, CombFilter::ThreeLine] {
+            let (mut luma, mut chroma) = (vec![0.0; 32], vec![0.0; 32]);
+            comb_filter(&line, &line, &line, mode, &mut luma, &mut chroma);
+            assert!(chroma.iter().all(|c| c.abs() < 1.0e-6));
+            assert_eq!(luma, line);
+        }
     }
 }
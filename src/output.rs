@@ -0,0 +1,411 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    Output image routines
+
+    Decoders write unclamped floating point RGB, so out-of-gamut and super-white
+    values survive until the image is saved.
+*/
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use cgmath::Vector3;
+use image::{ImageBuffer, Rgb, Rgba};
+use serde::{Deserialize, Serialize};
+
+use crate::composite::{CompositeSignal, HDOT_CLOCK, LINE_HDOTS};
+use crate::ntsc::to_u8_clamped;
+use crate::waveform::FrameTiming;
+
+// Nominal mapping of signal units to volts into 75 ohms: 0.0 is blanking and 1.0 is peak white.
+const VOLTAGE_OFFSET: f64 = 0.286;
+const VOLTAGE_SCALE: f64 = 0.714;
+
+/// File format for decoded output images.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// 8-bit per channel RGBA PNG.
+    Png8,
+    /// 16-bit per channel RGBA PNG.
+    Png16,
+    /// 32-bit float OpenEXR.
+    Exr,
+    /// 32-bit float Portable Float Map.
+    Pfm,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "png" | "png8" => Ok(OutputFormat::Png8),
+            "png16" => Ok(OutputFormat::Png16),
+            "exr" => Ok(OutputFormat::Exr),
+            "pfm" => Ok(OutputFormat::Pfm),
+            _ => Err("Bad value for format, expected png8, png16, exr or pfm".to_string()),
+        }
+    }
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png8 | OutputFormat::Png16 => "png",
+            OutputFormat::Exr => "exr",
+            OutputFormat::Pfm => "pfm",
+        }
+    }
+}
+
+/// Width and height of a frame, given as WxH.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FrameSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for FrameSize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (w, h) = s
+            .to_lowercase()
+            .split_once('x')
+            .and_then(|(w, h)| Some((w.trim().parse::<u32>().ok()?, h.trim().parse::<u32>().ok()?)))
+            .filter(|(w, h)| *w > 0 && *h > 0)
+            .ok_or_else(|| format!("Bad frame size '{}', expected WxH", s))?;
+        Ok(FrameSize { width: w, height: h })
+    }
+}
+
+/// An RGB image with one unclamped f32 triplet per pixel, nominally in the range 0-1.
+#[derive(Clone, Debug)]
+pub struct FloatImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[f32; 3]>,
+}
+
+impl FloatImage {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![[0.0; 3]; (width * height) as usize],
+        }
+    }
+
+    #[inline]
+    pub fn put(&mut self, index: usize, rgb: Vector3<f32>) {
+        self.pixels[index] = [rgb.x, rgb.y, rgb.z];
+    }
+
+    /// Apply a function to every pixel in place.
+    pub fn map_in_place(&mut self, f: impl Fn(Vector3<f32>) -> Vector3<f32>) {
+        for p in self.pixels.iter_mut() {
+            let rgb = f(Vector3::new(p[0], p[1], p[2]));
+            *p = [rgb.x, rgb.y, rgb.z];
+        }
+    }
+
+    /// Convert to 8-bit RGBA, clamping each channel.
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.pixels.len() * 4);
+        for p in &self.pixels {
+            out.extend_from_slice(&[
+                to_u8_clamped(p[0] * 255.0),
+                to_u8_clamped(p[1] * 255.0),
+                to_u8_clamped(p[2] * 255.0),
+                255,
+            ]);
+        }
+        out
+    }
+
+    /// Convert to 16-bit RGBA, clamping each channel.
+    pub fn to_rgba16(&self) -> Vec<u16> {
+        let to_u16 = |v: f32| (v * 65535.0 + 0.5).clamp(0.0, 65535.0) as u16;
+
+        let mut out = Vec::with_capacity(self.pixels.len() * 4);
+        for p in &self.pixels {
+            out.extend_from_slice(&[to_u16(p[0]), to_u16(p[1]), to_u16(p[2]), 65535]);
+        }
+        out
+    }
+
+    /// Save the image as `<stem>.<ext>` in the given format, returning the filename written.
+    pub fn save(&self, stem: &str, format: OutputFormat) -> Result<String, String> {
+        let filename = format!("{}.{}", stem, format.extension());
+
+        match format {
+            OutputFormat::Png8 => image::save_buffer(
+                &filename,
+                &self.to_rgba8(),
+                self.width,
+                self.height,
+                image::ColorType::Rgba8,
+            )
+            .map_err(|e| e.to_string())?,
+            OutputFormat::Png16 => {
+                ImageBuffer::<Rgba<u16>, _>::from_raw(self.width, self.height, self.to_rgba16())
+                    .ok_or("Bad image dimensions")?
+                    .save(&filename)
+                    .map_err(|e| e.to_string())?
+            }
+            OutputFormat::Exr => {
+                let data: Vec<f32> = bytemuck::cast_slice(&self.pixels).to_vec();
+                ImageBuffer::<Rgb<f32>, _>::from_raw(self.width, self.height, data)
+                    .ok_or("Bad image dimensions")?
+                    .save(&filename)
+                    .map_err(|e| e.to_string())?
+            }
+            OutputFormat::Pfm => self.save_pfm(&filename).map_err(|e| e.to_string())?,
+        }
+
+        Ok(filename)
+    }
+
+    /// Write a color Portable Float Map. Rows are stored bottom to top in little-endian order.
+    pub fn save_pfm(&self, filename: &str) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(filename)?);
+
+        write!(writer, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        for row in self.pixels.chunks(self.width as usize).rev() {
+            for p in row {
+                for c in p {
+                    writer.write_all(&c.to_le_bytes())?;
+                }
+            }
+        }
+        writer.flush()
+    }
+}
+
+/// Save a decoded image and report the result on the console.
+pub fn save_output(img: &FloatImage, stem: &str, format: OutputFormat) {
+    match img.save(stem, format) {
+        Ok(filename) => println!("Wrote {}!", filename.trim_start_matches("./")),
+        Err(e) => {
+            println!("Error writing output file: {}", e)
+        }
+    }
+}
+
+/// Timing and level information written alongside an exported composite signal.
+#[derive(Serialize, Deserialize)]
+pub struct SignalMetadata {
+    /// Sample encoding, always little-endian 32-bit float.
+    pub dtype: String,
+    /// Sample rate in Hz.
+    pub sample_rate: f64,
+    /// Color subcarrier frequency in Hz.
+    pub subcarrier: f64,
+    pub samples_per_hdot: u32,
+    /// Position of sample 0 within the 4-hdot color clock cycle, in hdots.
+    pub hdot_phase: f64,
+    /// Samples per line in the exported data.
+    pub line_length: u32,
+    pub lines: u32,
+    /// Total hdots in a full CGA scanline, including blanking.
+    pub total_line_hdots: u32,
+    /// Volts = voltage_offset + sample * voltage_scale. These are nominal, not measured.
+    pub voltage_offset: f64,
+    pub voltage_scale: f64,
+    /// Sample positions of sync, burst and active video, present for full frame exports.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frame: Option<FrameTiming>,
+}
+
+impl SignalMetadata {
+    pub fn new(signal: &CompositeSignal, hdot_phase: f64) -> Self {
+        Self {
+            dtype: "<f4".to_string(),
+            sample_rate: signal.sample_rate(),
+            subcarrier: HDOT_CLOCK / 4.0,
+            samples_per_hdot: signal.samples_per_hdot,
+            hdot_phase,
+            line_length: signal.width,
+            lines: signal.height,
+            total_line_hdots: LINE_HDOTS,
+            voltage_offset: VOLTAGE_OFFSET,
+            voltage_scale: VOLTAGE_SCALE,
+            frame: None,
+        }
+    }
+
+    /// Metadata for a full frame built by `waveform::encode_frame`.
+    pub fn for_frame(signal: &CompositeSignal) -> Self {
+        Self {
+            frame: Some(FrameTiming::new(signal.samples_per_hdot)),
+            ..Self::new(signal, 0.0)
+        }
+    }
+}
+
+/// Export a composite signal as f32 samples. Files ending in .npy are written as a 2D NumPy
+/// array of lines; anything else is written as raw little-endian f32. Timing metadata is
+/// written next to it with a .json extension appended.
+pub fn save_signal(signal: &CompositeSignal, metadata: &SignalMetadata, path: &Path) -> Result<(), String> {
+    let is_npy = path
+        .extension()
+        .map(|e| e.eq_ignore_ascii_case("npy"))
+        .unwrap_or(false);
+
+    let mut writer = BufWriter::new(File::create(path).map_err(|e| e.to_string())?);
+
+    if is_npy {
+        write_npy_header(&mut writer, signal.height, signal.width).map_err(|e| e.to_string())?;
+    }
+    for s in &signal.samples {
+        writer.write_all(&s.to_le_bytes()).map_err(|e| e.to_string())?;
+    }
+    writer.flush().map_err(|e| e.to_string())?;
+
+    let mut meta_path = path.as_os_str().to_owned();
+    meta_path.push(".json");
+    let json = serde_json::to_string_pretty(metadata).map_err(|e| e.to_string())?;
+    std::fs::write(&meta_path, json).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+/// Read a composite signal written by `save_signal`, or captured in the same layout, along with
+/// the metadata in its .json file. The metadata gives the shape of raw files; .npy files must
+/// hold little-endian f32 samples of the same shape.
+pub fn load_signal(path: &Path) -> Result<(CompositeSignal, SignalMetadata), String> {
+    let mut meta_path = path.as_os_str().to_owned();
+    meta_path.push(".json");
+    let json = std::fs::read_to_string(&meta_path)
+        .map_err(|e| format!("Couldn't open signal metadata '{:?}': {}", meta_path, e))?;
+    let metadata: SignalMetadata =
+        serde_json::from_str(&json).map_err(|e| format!("Bad signal metadata '{:?}': {}", meta_path, e))?;
+    if metadata.dtype != "<f4" {
+        return Err(format!("Unsupported sample type '{}', expected <f4", metadata.dtype));
+    }
+
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(|e| format!("Couldn't open signal '{:?}': {}", path, e))?;
+    let body = if data.starts_with(b"\x93NUMPY") {
+        npy_body(&data)?
+    } else {
+        &data[..]
+    };
+
+    let mut signal = CompositeSignal::new(metadata.line_length, metadata.lines, metadata.samples_per_hdot);
+    if body.len() != signal.samples.len() * 4 {
+        return Err(format!(
+            "Signal holds {} bytes, its metadata describes {} lines of {} samples",
+            body.len(),
+            metadata.lines,
+            metadata.line_length
+        ));
+    }
+    for (s, bytes) in signal.samples.iter_mut().zip(body.chunks_exact(4)) {
+        *s = f32::from_le_bytes(bytes.try_into().unwrap());
+    }
+    Ok((signal, metadata))
+}
+
+/// Return the array data of a NumPy file, checking that it holds C-ordered little-endian f32.
+fn npy_body(data: &[u8]) -> Result<&[u8], String> {
+    // Version 1.0 has a 2 byte header length, later versions 4 bytes.
+    let (len_size, header_start) = if data.get(6) == Some(&1) { (2, 10) } else { (4, 12) };
+    if data.len() < header_start {
+        return Err("Truncated NumPy header".to_string());
+    }
+    let mut len = [0; 4];
+    len[..len_size].copy_from_slice(&data[8..8 + len_size]);
+    let header_end = header_start + u32::from_le_bytes(len) as usize;
+
+    let header = data
+        .get(header_start..header_end)
+        .map(String::from_utf8_lossy)
+        .ok_or("Truncated NumPy header")?;
+    if !header.contains("'<f4'") || header.contains("True") {
+        return Err(format!("Unsupported NumPy array '{}', expected C-ordered <f4", header.trim()));
+    }
+    Ok(&data[header_end..])
+}
+
+/// Write a version 1.0 NumPy header for a C-ordered little-endian f32 array of the given shape.
+fn write_npy_header(writer: &mut impl Write, rows: u32, cols: u32) -> std::io::Result<()> {
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+        rows, cols
+    );
+    // Magic, version and length take 10 bytes; pad so the data starts on a 64 byte boundary.
+    let total = (10 + header.len() + 1).div_ceil(64) * 64;
+    while 10 + header.len() + 1 < total {
+        header.push(' ');
+    }
+    header.push('\n');
+
+    writer.write_all(b"\x93NUMPY\x01\x00")?;
+    writer.write_all(&(header.len() as u16).to_le_bytes())?;
+    writer.write_all(header.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(file_name: &str) {
+        let mut signal = CompositeSignal::new(12, 3, 2);
+        for (i, s) in signal.samples.iter_mut().enumerate() {
+            *s = i as f32 * 0.25 - 1.5;
+        }
+        let metadata = SignalMetadata::new(&signal, 1.5);
+
+        let path = std::env::temp_dir().join(format!("{}-{}", std::process::id(), file_name));
+        save_signal(&signal, &metadata, &path).unwrap();
+        let loaded = load_signal(&path);
+        let mut meta_path = path.as_os_str().to_owned();
+        meta_path.push(".json");
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&meta_path);
+
+        let (loaded, loaded_metadata) = loaded.unwrap();
+        assert_eq!((loaded.width, loaded.height), (12, 3));
+        assert_eq!(loaded.samples_per_hdot, 2);
+        assert_eq!(loaded.samples, signal.samples);
+        assert_eq!(loaded_metadata.hdot_phase, 1.5);
+        assert_eq!(loaded_metadata.sample_rate, signal.sample_rate());
+    }
+
+    #[test]
+    fn npy_signal_round_trips() {
+        round_trip("signal.npy");
+    }
+
+    #[test]
+    fn raw_signal_round_trips() {
+        round_trip("signal.raw");
+    }
+}
@@ -0,0 +1,260 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    Artifact color palette export
+
+    Every pixel pattern a mode can put in one color clock is repeated over a
+    flat field and decoded, giving the fixed set of artifact colors available
+    in that mode. The palette is written for paint programs as a GIMP .gpl, a
+    JASC .pal, a PNG swatch and JSON.
+
+    The same colors give a cheap decode of images drawn for the effective
+    160x200 16 color mode, where each color clock is one pixel.
+*/
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+
+use serde::Serialize;
+
+use crate::composite::{CompositeSignal, EncoderModel, ModelParams};
+use crate::inverse::{clock_patterns, CLOCK_HDOTS};
+use crate::ntsc::{self, DecodeSettings, OutputType};
+use crate::output::FloatImage;
+use crate::reenigne_composite::ReCompositeContext;
+use crate::vram::{Register, VRAM_HDOTS};
+use crate::{PaletteArgs, SampleMethod};
+
+const FIELD_LINES: u32 = 8; // Lines in the flat field, enough for the comb filters
+const SWATCH_SIZE: u32 = 32;
+const SWATCH_COLUMNS: usize = 16;
+
+/// One artifact color and the pattern of CGA color indices that produces it.
+#[derive(Clone, Debug, Serialize)]
+pub struct PaletteEntry {
+    pub pattern: [u8; CLOCK_HDOTS],
+    pub rgb: [u8; 3],
+    pub hex: String,
+}
+
+impl PaletteEntry {
+    /// Name the entry by its pattern, one hex digit per hdot.
+    fn name(&self) -> String {
+        self.pattern.iter().map(|i| format!("{:X}", i)).collect()
+    }
+}
+
+#[derive(Serialize)]
+struct PaletteFile<'a> {
+    mode: String,
+    color_select: u8,
+    method: String,
+    colors: &'a [PaletteEntry],
+}
+
+/// Run the palette subcommand.
+pub fn run(args: &PaletteArgs) -> Result<(), String> {
+    let mut regs = args.mode.registers();
+    if let Some(Register(value)) = args.color_reg {
+        regs.color_select = value;
+    }
+    let (pixel_hdots, colors) = regs.pixel_colors();
+
+    let entries: Vec<PaletteEntry> = clock_patterns(pixel_hdots, &colors)
+        .into_iter()
+        .map(|pattern| {
+            let rgb = decode_pattern(&pattern, regs.mode, args).map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8);
+            PaletteEntry {
+                pattern,
+                rgb,
+                hex: format!("#{:02x}{:02x}{:02x}", rgb[0], rgb[1], rgb[2]),
+            }
+        })
+        .collect();
+
+    let title = format!("CGA composite {:?} 3D9h={:02X}h", args.mode, regs.color_select);
+
+    write_file(&format!("{}.gpl", args.output), &to_gpl(&title, &entries))?;
+    write_file(&format!("{}.pal", args.output), &to_jasc(&entries))?;
+
+    let json = serde_json::to_string_pretty(&PaletteFile {
+        mode: format!("{:?}", args.mode),
+        color_select: regs.color_select,
+        method: format!("{:?}", args.method),
+        colors: &entries,
+    })
+    .map_err(|e| e.to_string())?;
+    write_file(&format!("{}.json", args.output), &json)?;
+
+    save_swatch(&format!("{}.png", args.output), &entries)?;
+    Ok(())
+}
+
+/// Decode a pattern with the palette's chosen decoder, in the given mode register setting.
+fn decode_pattern(pattern: &[u8; CLOCK_HDOTS], mode: u8, args: &PaletteArgs) -> [f32; 3] {
+    flat_field_color(pattern, &mut |field, w, h| match args.method {
+        SampleMethod::Reenigne | SampleMethod::Mono => {
+            let mut ctx = ReCompositeContext::new();
+            ctx.set_hue_offset(args.tint as f64);
+            ctx.set_monochrome(matches!(args.method, SampleMethod::Mono));
+            ctx.update_cga16_color(mode);
+            ctx.decode_image(field, w, h)
+        }
+        _ => {
+            let signal = CompositeSignal::encode(field, w, h, 2, &EncoderModel::Digital, &ModelParams::default());
+            let settings = DecodeSettings {
+                hue: args.hue + args.tint.to_radians(),
+                sat: args.sat,
+                luma: args.luma,
+                ..Default::default()
+            };
+            let mut rgb_out = FloatImage::new(w, h);
+            ntsc::process(&signal, &mut rgb_out, args.method, &settings, OutputType::Rgb);
+            rgb_out
+        }
+    })
+}
+
+/// Decode a pattern repeated over a flat field with `decode_field`, and return the average
+/// color of one color clock in the middle of it.
+pub fn flat_field_color(
+    pattern: &[u8; CLOCK_HDOTS],
+    decode_field: &mut impl FnMut(&[u8], u32, u32) -> FloatImage,
+) -> [f32; 3] {
+    let w = VRAM_HDOTS;
+    let field: Vec<u8> = pattern.iter().copied().cycle().take((w * FIELD_LINES) as usize).collect();
+    let decoded = decode_field(&field, w, FIELD_LINES);
+
+    let o = ((FIELD_LINES / 2) * w + w / 2) as usize;
+    let mut sum = [0.0; 3];
+    for p in &decoded.pixels[o..o + CLOCK_HDOTS] {
+        for c in 0..3 {
+            sum[c] += p[c] / CLOCK_HDOTS as f32;
+        }
+    }
+    sum
+}
+
+/// The flat field colors of 4 hdot patterns, decoded the first time each pattern is seen and
+/// kept for every frame decoded with the same settings.
+#[derive(Default)]
+pub struct PatternColors {
+    colors: HashMap<[u8; CLOCK_HDOTS], [f32; 3]>,
+    warned: bool,
+}
+
+impl PatternColors {
+    /// Decode an image as 4 hdot wide pixels, mapping the pattern in each color clock to its
+    /// flat field color. New patterns are decoded with `decode_field`. The output is a quarter
+    /// of the input width if `narrow` is set, otherwise each color is repeated over its 4 hdots.
+    pub fn decode(
+        &mut self,
+        cga_buf: &[u8],
+        img_w: u32,
+        img_h: u32,
+        narrow: bool,
+        mut decode_field: impl FnMut(&[u8], u32, u32) -> FloatImage,
+    ) -> FloatImage {
+        let clocks = (img_w as usize) / CLOCK_HDOTS;
+        let out_w = if narrow { clocks as u32 } else { img_w };
+        let mut rgb_out = FloatImage::new(out_w, img_h);
+
+        for (in_line, out_line) in cga_buf.chunks(img_w as usize).zip(rgb_out.pixels.chunks_mut(out_w as usize)) {
+            for clock in 0..clocks {
+                let pattern: [u8; CLOCK_HDOTS] = in_line[clock * CLOCK_HDOTS..(clock + 1) * CLOCK_HDOTS].try_into().unwrap();
+                let rgb = *self
+                    .colors
+                    .entry(pattern)
+                    .or_insert_with(|| flat_field_color(&pattern, &mut decode_field));
+
+                if narrow {
+                    out_line[clock] = rgb;
+                } else {
+                    out_line[clock * CLOCK_HDOTS..(clock + 1) * CLOCK_HDOTS].fill(rgb);
+                }
+            }
+        }
+
+        // Images made for the 160x200 mode use a single foreground color over black. Standard
+        // output may be carrying piped frames, so warn on standard error, once per setup.
+        let used: HashSet<u8> = cga_buf.iter().copied().collect();
+        if !self.warned && (used.len() > 2 || (used.len() == 2 && !used.contains(&0))) {
+            eprintln!(
+                "Image uses {} colors and is not a 640x200 mono image; decoding each 4 hdot pattern as a color",
+                used.len()
+            );
+            self.warned = true;
+        }
+        rgb_out
+    }
+}
+
+fn write_file(path: &str, text: &str) -> Result<(), String> {
+    std::fs::write(path, text).map_err(|e| format!("Error writing {}: {}", path, e))?;
+    println!("Wrote {}!", path);
+    Ok(())
+}
+
+/// Format a GIMP palette.
+fn to_gpl(title: &str, entries: &[PaletteEntry]) -> String {
+    let mut s = format!("GIMP Palette\nName: {}\nColumns: {}\n#\n", title, SWATCH_COLUMNS.min(entries.len()));
+    for e in entries {
+        let _ = writeln!(s, "{:3} {:3} {:3}\t{}", e.rgb[0], e.rgb[1], e.rgb[2], e.name());
+    }
+    s
+}
+
+/// Format a JASC (Paint Shop Pro) palette.
+fn to_jasc(entries: &[PaletteEntry]) -> String {
+    let mut s = format!("JASC-PAL\r\n0100\r\n{}\r\n", entries.len());
+    for e in entries {
+        let _ = write!(s, "{} {} {}\r\n", e.rgb[0], e.rgb[1], e.rgb[2]);
+    }
+    s
+}
+
+/// Write the palette as a grid of square swatches.
+fn save_swatch(path: &str, entries: &[PaletteEntry]) -> Result<(), String> {
+    let columns = SWATCH_COLUMNS.min(entries.len()) as u32;
+    let rows = entries.len().div_ceil(SWATCH_COLUMNS) as u32;
+    let (w, h) = (columns * SWATCH_SIZE, rows * SWATCH_SIZE);
+
+    let mut buf = vec![0u8; (w * h * 3) as usize];
+    for (i, e) in entries.iter().enumerate() {
+        let x0 = (i as u32 % columns) * SWATCH_SIZE;
+        let y0 = (i as u32 / columns) * SWATCH_SIZE;
+        for y in y0..y0 + SWATCH_SIZE {
+            for x in x0..x0 + SWATCH_SIZE {
+                let o = ((y * w + x) * 3) as usize;
+                buf[o..o + 3].copy_from_slice(&e.rgb);
+            }
+        }
+    }
+
+    image::save_buffer(path, &buf, w, h, image::ColorType::Rgb8).map_err(|e| format!("Error writing {}: {}", path, e))?;
+    println!("Wrote {}!", path);
+    Ok(())
+}
@@ -0,0 +1,189 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    Raw video pipe
+
+    Reads fixed size raw frames from standard input, either RGBA or one CGA
+    color index per pixel, and writes each decoded frame to standard output
+    as raw RGBA, so the decoders can sit between two ffmpeg processes.
+    Standard output carries nothing but frames; messages go to standard
+    error.
+*/
+
+use std::io::{ErrorKind, Read, StdinLock, Write};
+use std::str::FromStr;
+
+use crate::field;
+use crate::output::{FloatImage, FrameSize};
+use crate::rgbi::{self, MatchOptions, SourcePalette};
+use crate::waveform::FRAME_LINES;
+use crate::double_low_res;
+
+/// Layout of raw input frames.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PipeFormat {
+    /// 4 bytes per pixel, matched to CGA colors like an image.
+    Rgba,
+    /// One CGA color index (0-15) per pixel.
+    Index,
+}
+
+impl FromStr for PipeFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "rgba" => Ok(PipeFormat::Rgba),
+            "index" => Ok(PipeFormat::Index),
+            _ => Err("Bad value for pipe format, expected rgba or index".to_string()),
+        }
+    }
+}
+
+/// Reads raw frames from standard input and converts them to CGA color indices.
+pub struct PipeReader {
+    format: PipeFormat,
+    size: FrameSize,
+    matching: MatchOptions,
+    /// Source palette of RGBA frames, chosen with the first frame.
+    palette: Option<SourcePalette>,
+    /// Border color to assemble each frame into a whole field with, if any.
+    field: Option<u8>,
+    stdin: StdinLock<'static>,
+}
+
+impl PipeReader {
+    pub fn new(format: PipeFormat, size: FrameSize, matching: MatchOptions, field: Option<u8>) -> Result<Self, String> {
+        if size.width != 320 && size.width != 640 {
+            return Err(format!("Unsupported frame width: {}", size.width));
+        }
+        Ok(Self {
+            format,
+            size,
+            matching,
+            palette: None,
+            field,
+            stdin: std::io::stdin().lock(),
+        })
+    }
+
+    /// Read the next frame as CGA color indices, one per hdot, with its width and height,
+    /// assembled into a field if asked. Returns None at the end of the input.
+    pub fn next_frame(&mut self) -> Result<Option<(Vec<u8>, u32, u32)>, String> {
+        let pixels = (self.size.width * self.size.height) as usize;
+        let bytes_per_pixel = match self.format {
+            PipeFormat::Rgba => 4,
+            PipeFormat::Index => 1,
+        };
+
+        let mut buf = vec![0u8; pixels * bytes_per_pixel];
+        if !read_frame(&mut self.stdin, &mut buf)? {
+            return Ok(None);
+        }
+
+        let cga_buf = match self.format {
+            PipeFormat::Index => {
+                if let Some(i) = buf.iter().find(|&&i| i >= 16) {
+                    return Err(format!("Input has index {}, which is not a CGA color", i));
+                }
+                buf
+            }
+            PipeFormat::Rgba => {
+                if self.palette.is_none() {
+                    self.palette = Some(match &self.matching.palette {
+                        Some(name) => SourcePalette::load(name)?,
+                        None => rgbi::detect_palette(&buf),
+                    });
+                }
+                let palette = self.palette.as_ref().unwrap();
+                let (cga_buf, report) = rgbi::match_image(&buf, self.size.width, palette, self.matching.tolerance);
+                // Standard output carries the frames, so report on standard error.
+                if report.unmatched > 0 {
+                    eprintln!(
+                        "{} pixels are not within tolerance of the '{}' palette",
+                        report.unmatched,
+                        report.palette
+                    );
+                }
+                cga_buf
+            }
+        };
+
+        let (cga_buf, width, height) = double_low_res(cga_buf, self.size.width, self.size.height);
+        match self.field {
+            Some(border) => Ok(Some((field::assemble(&cga_buf, width, height, border)?, width, FRAME_LINES))),
+            None => Ok(Some((cga_buf, width, height))),
+        }
+    }
+}
+
+/// Fill `buf` from the reader. Returns false if the input ended before the frame started.
+fn read_frame(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool, String> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(format!("Input ended partway through a frame ({} of {} bytes)", filled, buf.len())),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(format!("Error reading standard input: {}", e)),
+        }
+    }
+    Ok(true)
+}
+
+/// Decode the first frame and every following frame on standard input, writing each to
+/// standard output as raw 8 bit RGBA. Stops quietly if the reader on the other end goes away.
+pub fn stream(
+    reader: &mut PipeReader,
+    first: &[u8],
+    mut decode_frame: impl FnMut(&[u8]) -> FloatImage,
+) -> Result<(), String> {
+    let mut stdout = std::io::stdout().lock();
+    let mut frame = first.to_vec();
+    let mut count = 0;
+
+    loop {
+        let rgb_out = decode_frame(&frame);
+        if count == 0 {
+            eprintln!("Writing {}x{} RGBA frames", rgb_out.width, rgb_out.height);
+        }
+
+        match stdout.write_all(&rgb_out.to_rgba8()).and_then(|_| stdout.flush()) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => break,
+            Err(e) => return Err(format!("Error writing standard output: {}", e)),
+        }
+        count += 1;
+
+        match reader.next_frame()? {
+            Some((next, _, _)) => frame = next,
+            None => break,
+        }
+    }
+
+    eprintln!("Decoded {} frames", count);
+    Ok(())
+}
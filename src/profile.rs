@@ -0,0 +1,152 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    Card and monitor profiles
+
+    A profile describes a CGA card revision and the monitor it drives, in TOML
+    or JSON. Anything a profile leaves out falls back to the built-in defaults,
+    and anything given on the command line overrides the profile.
+*/
+
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::colorimetry::{DecodeMatrix, Primaries};
+use crate::composite::ModelParams;
+use crate::crt::MaskType;
+use crate::ntsc::CombFilter;
+
+/// Profiles bundled with the program, by name.
+pub const BUILTIN_PROFILES: [(&str, &str); 3] = [
+    ("ibm5153-old-cga", include_str!("../profiles/ibm5153-old-cga.toml")),
+    ("1702-new-cga", include_str!("../profiles/1702-new-cga.toml")),
+    ("dosbox", include_str!("../profiles/dosbox.toml")),
+];
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub card: CardProfile,
+    pub monitor: MonitorProfile,
+}
+
+/// The card revision: its output levels and chroma multiplexer.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct CardProfile {
+    /// Model the later CGA revision, which mixes the RGB outputs into the composite signal.
+    pub new_cga: bool,
+    /// Replacement 256 entry chroma multiplexer table for the Reenigne decoder.
+    pub chroma_multiplexer: Option<Vec<u8>>,
+    /// Encoder levels and intensity table, as written by the calibrate subcommand.
+    #[serde(flatten)]
+    pub model: ModelParams,
+}
+
+/// The monitor: its filters and picture controls.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MonitorProfile {
+    pub hue: Option<f32>,
+    pub sat: Option<f32>,
+    pub luma: Option<f32>,
+    /// Hue offset in degrees.
+    pub tint: Option<f32>,
+    /// Luma, I and Q bandwidths in MHz. Only the filtered decoder has separate filters to
+    /// set; the fast and accurate decoders use fixed sampling windows.
+    pub luma_bw: Option<f32>,
+    pub i_bw: Option<f32>,
+    pub q_bw: Option<f32>,
+    #[serde(deserialize_with = "from_str_opt")]
+    pub comb: Option<CombFilter>,
+    #[serde(deserialize_with = "from_str_opt")]
+    pub matrix: Option<DecodeMatrix>,
+    #[serde(deserialize_with = "from_str_opt")]
+    pub primaries: Option<Primaries>,
+    /// Picture controls of the Reenigne decoder, in its own units.
+    pub brightness: Option<f64>,
+    pub contrast: Option<f64>,
+    pub saturation: Option<f64>,
+    pub sharpness: Option<f64>,
+    /// CRT presentation, used with --crt.
+    pub scanlines: Option<f32>,
+    #[serde(deserialize_with = "from_str_opt")]
+    pub mask: Option<MaskType>,
+    pub mask_strength: Option<f32>,
+    pub mask_pitch: Option<u32>,
+    pub beam_spread: Option<f32>,
+    pub bloom: Option<f32>,
+    pub curvature: Option<f32>,
+}
+
+/// Deserialize an optional value from a string using its FromStr implementation.
+fn from_str_opt<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr<Err = String>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+impl Profile {
+    /// Load a profile from a file, or a bundled profile by name. Files ending in .json are
+    /// read as JSON, anything else as TOML.
+    pub fn load(name: &str) -> Result<Self, String> {
+        let path = Path::new(name);
+
+        if path.is_file() {
+            let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+            let is_json = path
+                .extension()
+                .map(|e| e.eq_ignore_ascii_case("json"))
+                .unwrap_or(false);
+            if is_json {
+                serde_json::from_str(&text).map_err(|e| e.to_string())
+            } else {
+                Profile::from_toml(&text)
+            }
+        } else if let Some((_, text)) = BUILTIN_PROFILES.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+            Profile::from_toml(text)
+        } else {
+            let names: Vec<&str> = BUILTIN_PROFILES.iter().map(|(n, _)| *n).collect();
+            Err(format!(
+                "No profile file or built-in profile named '{}'. Built-in profiles: {}",
+                name,
+                names.join(", ")
+            ))
+        }
+    }
+
+    fn from_toml(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| e.to_string())
+    }
+}
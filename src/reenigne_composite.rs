@@ -9,7 +9,7 @@
     
 */
 
-const CHROMA_MULTIPLEXER: [u8; 256] = [
+pub const CHROMA_MULTIPLEXER: [u8; 256] = [
 	  2,   2,   2,   2, 114, 174,   4,   3,   2,   1, 133, 135,   2, 113, 150,   4,
 	133,   2,   1,  99, 151, 152,   2,   1,   3,   2,  96, 136, 151, 152, 151, 152,
 	  2,  56,  62,   4, 111, 250, 118,   4,   0,  51, 207, 137,   1, 171, 209,   5,
@@ -28,14 +28,41 @@ const CHROMA_MULTIPLEXER: [u8; 256] = [
 	198,  59,  58, 169, 250, 251,  81,  80, 100,  58, 154, 250, 251, 252, 252, 252
 ];
 
-const INTENSITY: [f64; 4] = [
+pub const INTENSITY: [f64; 4] = [
     77.175381, 88.654656, 166.564623, 174.228438
 ];
 
-const TAU: f64 = 6.28318531;
+use std::f64::consts::TAU;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::colorimetry::DecodeMatrix;
+use crate::output::FloatImage;
 
 const SCALER_MAXWIDTH: usize = 2048;
 
+/// A chroma multiplexer table and the intensity levels measured with it, as a JSON file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MuxTable {
+    pub chroma_multiplexer: Vec<u8>,
+    /// Levels of the four intensity outputs. Tables without them keep the current levels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub intensity: Option<[f64; 4]>,
+}
+
+impl MuxTable {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&text).map_err(|e| e.to_string())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(path, json).map_err(|e| e.to_string())
+    }
+}
+
 macro_rules! new_cga {
     ($c:expr, $i:expr, $r:expr, $g:expr, $b:expr) => {
         (($c as f64) / 0.72) * 0.29 + (($i) / 0.28) * 0.32 + (($r) / 0.28) * 0.1 + (($g) / 0.28) * 0.22 + (($b) / 0.28) * 0.07
@@ -63,6 +90,28 @@ macro_rules! composite_convert {
     };
 }
 
+/// An output pixel that can be built from the decoder's fixed point RGB values.
+pub trait RePixel: Copy {
+    fn from_rgb(r: i32, g: i32, b: i32) -> Self;
+}
+
+/// Packed 0xAABBGGRR pixels, clamped to 8 bits per channel.
+impl RePixel for u32 {
+    #[inline]
+    fn from_rgb(r: i32, g: i32, b: i32) -> Self {
+        0xFF << 24 | (byte_clamp(b) as u32) << 16 | (byte_clamp(g) as u32) << 8 | byte_clamp(r) as u32
+    }
+}
+
+/// Unclamped floating point RGB pixels, where 1.0 corresponds to 255 in the packed format.
+impl RePixel for [f32; 3] {
+    #[inline]
+    fn from_rgb(r: i32, g: i32, b: i32) -> Self {
+        const SCALE: f32 = 1.0 / ((1 << 13) as f32 * 255.0);
+        [r as f32 * SCALE, g as f32 * SCALE, b as f32 * SCALE]
+    }
+}
+
 pub struct ReCompositeBuffers {
     temp: [i32; SCALER_MAXWIDTH + 10],
     atemp: [i32; SCALER_MAXWIDTH + 2],
@@ -107,7 +156,11 @@ pub struct ReCompositeContext {
     tandy_mode_control: u32,
     
     cgamode: u8,
-    new_cga: bool
+    new_cga: bool,
+    iq_coefficients: [[f64; 2]; 3],
+    intensity: [f64; 4],
+    chroma_multiplexer: [u8; 256],
+    monochrome: bool,
 }
 
 impl ReCompositeContext {
@@ -137,7 +190,11 @@ impl ReCompositeContext {
             tandy_mode_control: 0,
             
             cgamode: 0,
-            new_cga: false
+            new_cga: false,
+            iq_coefficients: DecodeMatrix::FccYiq.iq_coefficients(),
+            intensity: INTENSITY,
+            chroma_multiplexer: CHROMA_MULTIPLEXER,
+            monochrome: false,
         }
     }
 
@@ -166,21 +223,16 @@ impl ReCompositeContext {
         let mut i0: f64;
         let mut i3: f64;
 
-        const RI: f64 = 0.9563;
-        const RQ: f64 = 0.6210;
-        const GI: f64 = -0.2721;
-        const GQ: f64 = -0.6474;
-        const BI: f64 = -1.1069;
-        const BQ: f64 = 1.7046;
+        let [[ri, rq], [gi, gq], [bi, bq]] = self.iq_coefficients;
 
         if !self.new_cga {
-            self.min_v = CHROMA_MULTIPLEXER[0] as f64 + INTENSITY[0];
-            self.max_v = CHROMA_MULTIPLEXER[255] as f64 + INTENSITY[3];
+            self.min_v = self.chroma_multiplexer[0] as f64 + self.intensity[0];
+            self.max_v = self.chroma_multiplexer[255] as f64 + self.intensity[3];
         } else {
-            i0 = INTENSITY[0];
-            i3 = INTENSITY[3];
-            self.min_v = new_cga!(CHROMA_MULTIPLEXER[0], i0, i0, i0, i0);
-            self.max_v = new_cga!(CHROMA_MULTIPLEXER[255], i3, i3, i3, i3);
+            i0 = self.intensity[0];
+            i3 = self.intensity[3];
+            self.min_v = new_cga!(self.chroma_multiplexer[0], i0, i0, i0, i0);
+            self.max_v = new_cga!(self.chroma_multiplexer[255], i3, i3, i3, i3);
         }
         self.mode_contrast = 256.0 / (self.max_v - self.min_v);
         self.mode_brightness = -self.min_v * self.mode_contrast;
@@ -207,18 +259,18 @@ impl ReCompositeContext {
                 rc = (right & 8) | (if (right & 7) != 0 { 7 } else { 0 });
                 lc = (left & 8)  | (if (left & 7)  != 0 { 7 } else { 0 });
             }
-            c = CHROMA_MULTIPLEXER[((lc & 7) << 5) | ((rc & 7) << 2) | phase] as f64;
-            i = INTENSITY[(left >> 3) | ((right >> 2) & 2)];
+            c = self.chroma_multiplexer[((lc & 7) << 5) | ((rc & 7) << 2) | phase] as f64;
+            i = self.intensity[(left >> 3) | ((right >> 2) & 2)];
             if !self.new_cga {
                 v = c + i;
             } 
             else {
-                let r = INTENSITY[((left >> 2) & 1) | ((right >> 1) & 2)];
-                let g = INTENSITY[((left >> 1) & 1) | (right & 2)];
-                let b = INTENSITY[(left & 1) | ((right << 1) & 2)];
+                let r = self.intensity[((left >> 2) & 1) | ((right >> 1) & 2)];
+                let g = self.intensity[((left >> 1) & 1) | (right & 2)];
+                let b = self.intensity[(left & 1) | ((right << 1) & 2)];
                 v = new_cga!(c, i, r, g, b);
             }
-            self.composite_table[x as usize] = (v * self.mode_contrast + self.mode_brightness) as i32;
+            self.composite_table[x] = (v * self.mode_contrast + self.mode_brightness) as i32;
         }
 
         i = (self.composite_table[6 * 68] - self.composite_table[6 * 68 + 2]) as f64;
@@ -232,34 +284,114 @@ impl ReCompositeContext {
         iq_adjust_i = -(i * c + q * s) * r;
         iq_adjust_q =  (q * c - i * s) * r;
 
-        self.video_ri = ( RI * iq_adjust_i + RQ * iq_adjust_q) as i32;
-        self.video_rq = (-RI * iq_adjust_q + RQ * iq_adjust_i) as i32;
-        self.video_gi = ( GI * iq_adjust_i + GQ * iq_adjust_q) as i32;
-        self.video_gq = (-GI * iq_adjust_q + GQ * iq_adjust_i) as i32;
-        self.video_bi = ( BI * iq_adjust_i + BQ * iq_adjust_q) as i32;
-        self.video_bq = (-BI * iq_adjust_q + BQ * iq_adjust_i) as i32;
+        self.video_ri = ( ri * iq_adjust_i + rq * iq_adjust_q) as i32;
+        self.video_rq = (-ri * iq_adjust_q + rq * iq_adjust_i) as i32;
+        self.video_gi = ( gi * iq_adjust_i + gq * iq_adjust_q) as i32;
+        self.video_gq = (-gi * iq_adjust_q + gq * iq_adjust_i) as i32;
+        self.video_bi = ( bi * iq_adjust_i + bq * iq_adjust_q) as i32;
+        self.video_bq = (-bi * iq_adjust_q + bq * iq_adjust_i) as i32;
 
         self.video_sharpness = (self.sharpness * 256.0 / 100.0) as i32;
 
         self.cgamode = cgamode;
     }    
 
+    /// Select the matrix used to convert decoded chroma to RGB. Takes effect on the next call
+    /// to update_cga16_color().
+    pub fn set_decode_matrix(&mut self, matrix: DecodeMatrix) {
+        self.iq_coefficients = matrix.iq_coefficients();
+    }
+
+    /// Set the levels of the four intensity outputs. Takes effect on the next call to
+    /// update_cga16_color().
+    pub fn set_intensity(&mut self, intensity: [f64; 4]) {
+        self.intensity = intensity;
+    }
+
+    /// Replace the chroma multiplexer table, which must have 256 entries. Takes effect on the
+    /// next call to update_cga16_color().
+    pub fn set_chroma_multiplexer(&mut self, table: &[u8]) -> Result<(), String> {
+        self.chroma_multiplexer = table
+            .try_into()
+            .map_err(|_| format!("Chroma multiplexer table must have 256 entries, found {}", table.len()))?;
+        Ok(())
+    }
+
+    /// Load a multiplexer table and, if it has them, its intensity levels. Takes effect on the
+    /// next call to update_cga16_color().
+    pub fn set_mux_table(&mut self, table: &MuxTable) -> Result<(), String> {
+        self.set_chroma_multiplexer(&table.chroma_multiplexer)?;
+        if let Some(intensity) = table.intensity {
+            self.intensity = intensity;
+        }
+        Ok(())
+    }
+
+    /// Return the current multiplexer table and intensity levels.
+    pub fn mux_table(&self) -> MuxTable {
+        MuxTable {
+            chroma_multiplexer: self.chroma_multiplexer.to_vec(),
+            intensity: Some(self.intensity),
+        }
+    }
+
+    /// Select the later CGA revision, which mixes the RGB outputs into the composite signal.
+    /// Takes effect on the next call to update_cga16_color().
+    pub fn set_new_cga(&mut self, new_cga: bool) {
+        self.new_cga = new_cga;
+    }
+
+    /// Decode for a monochrome monitor, which displays the composite table's luma with no
+    /// chroma separation.
+    pub fn set_monochrome(&mut self, monochrome: bool) {
+        self.monochrome = monochrome;
+    }
+
+    /// Set the picture controls. Brightness is an offset, contrast, saturation and sharpness
+    /// are percentages. Takes effect on the next call to update_cga16_color().
+    pub fn set_controls(&mut self, brightness: f64, contrast: f64, saturation: f64, sharpness: f64) {
+        self.brightness = brightness;
+        self.contrast = contrast;
+        self.saturation = saturation;
+        self.sharpness = sharpness;
+    }
+
+    /// Set the hue offset in degrees. Takes effect on the next call to update_cga16_color().
+    pub fn set_hue_offset(&mut self, h: f64) {
+        self.hue_offset = h;
+    }
+
     pub fn adjust(&mut self, h: f64, s: f64, b: f64) {
         self.hue_offset = h;
         self.saturation = s;
         self.brightness = b;
     }
 
-    pub fn composite_process(
+    /// Decode every row of a CGA index image into floating point RGB.
+    pub fn decode_image(&mut self, cga_buf: &[u8], img_w: u32, img_h: u32) -> FloatImage {
+        let mut buffers = ReCompositeBuffers::new();
+        let mut rgb_out = FloatImage::new(img_w, img_h);
+
+        for (in_line, out_line) in cga_buf
+            .chunks(img_w as usize)
+            .zip(rgb_out.pixels.chunks_mut(img_w as usize))
+        {
+            self.composite_process(0, img_w as usize, &mut buffers, in_line, out_line);
+        }
+        rgb_out
+    }
+
+    #[allow(clippy::explicit_counter_loop)]
+    pub fn composite_process<T: RePixel>(
         &mut self,
         border: u8,
         w: usize,
         buffers: &mut ReCompositeBuffers,
         in_line: &[u8],
-        out_line: &mut [u32],
+        out_line: &mut [T],
     ) {
 
-        let blocks = (w / 4) as usize;
+        let blocks = w / 4;
 
         let mut o_index = 0;
         let mut rgbi_index = 0;
@@ -270,16 +402,16 @@ impl ReCompositeContext {
             o_index += 1;
         }
 
-        buffers.temp[o_index] = self.composite_table[(((border as u32) << 6) | (((in_line[rgbi_index] & 0x0f) as u32) << 2) | 3) as usize] as i32;
+        buffers.temp[o_index] = self.composite_table[(((border as u32) << 6) | (((in_line[rgbi_index] & 0x0f) as u32) << 2) | 3) as usize];
         o_index += 1;
         
         for x in 0..w - 1 {
-            buffers.temp[o_index] = self.composite_table[(((in_line[rgbi_index] as usize & 0x0f) << 6) | ((in_line[rgbi_index + 1] as usize & 0x0f) << 2) | (x & 3)) as usize] as i32;
+            buffers.temp[o_index] = self.composite_table[(((in_line[rgbi_index] as usize & 0x0f) << 6) | ((in_line[rgbi_index + 1] as usize & 0x0f) << 2) | (x & 3))];
             o_index += 1;
             rgbi_index += 1;
         }
         
-        buffers.temp[o_index] = self.composite_table[(((in_line[rgbi_index] as u32 & 0x0f) << 6) | ((border as u32) << 2) | 3) as usize] as i32;
+        buffers.temp[o_index] = self.composite_table[(((in_line[rgbi_index] as u32 & 0x0f) << 6) | ((border as u32) << 2) | 3) as usize];
         o_index += 1;
         
         for x in 0..5 {
@@ -287,8 +419,8 @@ impl ReCompositeContext {
             o_index += 1;
         }
     
-        if false && (self.cgamode & 4) != 0 {
-            // Do high-res monochrome mode (shortcut)
+        if self.monochrome {
+            // Do monochrome decoding: the whole signal is luma
 
             let mut i_index = 5;
             let mut srgb_index = 0;
@@ -297,7 +429,7 @@ impl ReCompositeContext {
                 let d = (buffers.temp[i_index - 1] + buffers.temp[i_index + 1]) << 3;
                 let y = ((c + d) << 8) + self.video_sharpness * (c - d);
                 i_index += 1;
-                out_line[srgb_index] = byte_clamp(y) as u32 * 0x1010101;
+                out_line[srgb_index] = T::from_rgb(y, y, y);
                 srgb_index += 1;
             }
         } 
@@ -346,7 +478,7 @@ impl ReCompositeContext {
                 ap_index += 1;
                 bp_index += 1;
                 
-                out_line[srgb_index] = (0xFF << 24 | (byte_clamp(bb) as u32) << 16) | ((byte_clamp(gg) as u32) << 8) | (byte_clamp(rr) as u32); 
+                out_line[srgb_index] = T::from_rgb(rr, gg, bb);
                 srgb_index += 1;
 
                 // COMPOSITE_CONVERT(-b, a)
@@ -363,7 +495,7 @@ impl ReCompositeContext {
                 ap_index += 1;
                 bp_index += 1;
 
-                out_line[srgb_index] = (0xFF << 24 | (byte_clamp(bb) as u32) << 16) | ((byte_clamp(gg) as u32) << 8) | (byte_clamp(rr) as u32); 
+                out_line[srgb_index] = T::from_rgb(rr, gg, bb);
                 srgb_index += 1;
 
                 // COMPOSITE_CONVERT(-a, -b)
@@ -380,7 +512,7 @@ impl ReCompositeContext {
                 ap_index += 1;
                 bp_index += 1;
 
-                out_line[srgb_index] = (0xFF << 24 | (byte_clamp(bb) as u32) << 16) | ((byte_clamp(gg) as u32) << 8) | (byte_clamp(rr) as u32); 
+                out_line[srgb_index] = T::from_rgb(rr, gg, bb);
                 srgb_index += 1;
 
                 // COMPOSITE_CONVERT(b, -a)
@@ -397,7 +529,7 @@ impl ReCompositeContext {
                 ap_index += 1;
                 bp_index += 1;
 
-                out_line[srgb_index] = (0xFF << 24 | (byte_clamp(bb) as u32) << 16) | ((byte_clamp(gg) as u32) << 8) | (byte_clamp(rr) as u32); 
+                out_line[srgb_index] = T::from_rgb(rr, gg, bb);
                 srgb_index += 1;                                
             }
         }
@@ -407,5 +539,5 @@ impl ReCompositeContext {
 #[inline]
 fn byte_clamp(v: i32) -> u8
 {
-    return (v >> 13).clamp(0, 255) as u8;
+    (v >> 13).clamp(0, 255) as u8
 }
\ No newline at end of file
@@ -0,0 +1,253 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    RGBI source palettes
+
+    Screenshots of CGA images come from emulators and capture tools that
+    don't agree on the RGB values of the 16 colors: 6-bit VGA DACs give 0x54
+    and 0xA8 instead of 0x55 and 0xAA, and some show color 6 as dark yellow
+    instead of brown. Images are matched against a known palette, detected
+    from the image if not given, and pixels too far from any palette color
+    are reported instead of being silently mapped.
+*/
+
+use std::path::Path;
+
+use crate::composite::{rgb_distance_squared, RGBColor, CGA_RGB_TABLE};
+use crate::indexed::IndexMap;
+
+pub const DEFAULT_TOLERANCE: f32 = 32.0; // Maximum RGB distance for a pixel to match
+const MAX_EXAMPLES: usize = 8; // Unmatched colors listed in a match report
+
+/// A set of RGB values for the 16 RGBI colors.
+#[derive(Clone, Debug)]
+pub struct SourcePalette {
+    pub name: String,
+    pub colors: [RGBColor; 16],
+}
+
+/// Build an RGBI palette from its low and high levels, with either a brown or dark yellow
+/// color 6.
+fn rgbi_palette(name: &str, lo: u8, hi: u8, brown: bool) -> SourcePalette {
+    let mut colors = [RGBColor { r: 0, g: 0, b: 0 }; 16];
+    for (i, c) in colors.iter_mut().enumerate() {
+        let base = if i & 8 != 0 { lo } else { 0 };
+        let level = |bit: usize| if i & bit != 0 { hi } else { base };
+        *c = RGBColor {
+            r: level(4),
+            g: level(2),
+            b: level(1),
+        };
+    }
+    if brown {
+        colors[6].g = lo;
+    }
+    SourcePalette {
+        name: name.to_string(),
+        colors,
+    }
+}
+
+impl SourcePalette {
+    /// Return the built-in palettes. The first is the canonical CGA palette.
+    pub fn builtin() -> Vec<SourcePalette> {
+        vec![
+            SourcePalette {
+                name: "cga".to_string(),
+                colors: CGA_RGB_TABLE,
+            },
+            rgbi_palette("cga-dark-yellow", 0x55, 0xAA, false),
+            rgbi_palette("vga", 0x54, 0xA8, true),
+            rgbi_palette("vga-dark-yellow", 0x54, 0xA8, false),
+        ]
+    }
+
+    /// Return a built-in palette by name, or load a custom palette from a file of 16 colors,
+    /// one per line as RRGGBB or #RRGGBB, in RGBI order. Blank lines and lines starting with ;
+    /// or // are ignored.
+    pub fn load(name: &str) -> Result<Self, String> {
+        if let Some(p) = Self::builtin().into_iter().find(|p| p.name.eq_ignore_ascii_case(name)) {
+            return Ok(p);
+        }
+
+        let path = Path::new(name);
+        if !path.is_file() {
+            let names: Vec<String> = Self::builtin().into_iter().map(|p| p.name).collect();
+            return Err(format!(
+                "No palette file or built-in palette named '{}'. Built-in palettes: {}",
+                name,
+                names.join(", ")
+            ));
+        }
+
+        let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let colors = text
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with(';') && !l.starts_with("//"))
+            .map(parse_hex_color)
+            .collect::<Result<Vec<RGBColor>, String>>()?;
+
+        let count = colors.len();
+        let colors: [RGBColor; 16] = colors
+            .try_into()
+            .map_err(|_| format!("Palette file must have 16 colors, found {}", count))?;
+
+        Ok(SourcePalette {
+            name: path.display().to_string(),
+            colors,
+        })
+    }
+
+    /// Return the palette index closest to a color, and its squared distance.
+    #[inline]
+    fn nearest(&self, rgb: RGBColor) -> (u8, i32) {
+        self.colors
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (i as u8, rgb_distance_squared(rgb, *c)))
+            .min_by_key(|(_, d)| *d)
+            .unwrap()
+    }
+}
+
+fn parse_hex_color(s: &str) -> Result<RGBColor, String> {
+    let hex = s.trim_start_matches('#');
+    let value = u32::from_str_radix(hex, 16)
+        .ok()
+        .filter(|_| hex.len() == 6)
+        .ok_or_else(|| format!("Bad palette color '{}', expected RRGGBB", s))?;
+    Ok(RGBColor {
+        r: (value >> 16) as u8,
+        g: (value >> 8) as u8,
+        b: value as u8,
+    })
+}
+
+/// How to match the pixels of an image to CGA colors.
+#[derive(Clone, Debug)]
+pub struct MatchOptions {
+    /// Palette name or file; detected from the image if None.
+    pub palette: Option<String>,
+    pub tolerance: f32,
+    /// How the indices of palette indexed images are used.
+    pub index_map: IndexMap,
+}
+
+impl Default for MatchOptions {
+    fn default() -> Self {
+        Self {
+            palette: None,
+            tolerance: DEFAULT_TOLERANCE,
+            index_map: IndexMap::Auto,
+        }
+    }
+}
+
+/// How an image's pixels matched its source palette.
+#[derive(Debug)]
+pub struct MatchReport {
+    pub palette: String,
+    pub pixels: usize,
+    pub unmatched: usize,
+    /// Distance of the pixel furthest from its palette color.
+    pub max_distance: f32,
+    /// Some of the unmatched colors, with the position of their first pixel.
+    pub examples: Vec<(u32, u32, RGBColor)>,
+}
+
+impl MatchReport {
+    pub fn print(&self) {
+        if self.unmatched == 0 {
+            return;
+        }
+        println!(
+            "{} of {} pixels ({:.2}%) are not within tolerance of the '{}' palette; the worst is {:.1} away",
+            self.unmatched,
+            self.pixels,
+            100.0 * self.unmatched as f32 / self.pixels as f32,
+            self.palette,
+            self.max_distance
+        );
+        for (x, y, c) in &self.examples {
+            println!("  #{:02X}{:02X}{:02X} at {},{}", c.r, c.g, c.b, x, y);
+        }
+    }
+}
+
+/// Choose the built-in palette that matches the most pixels of an RGBA image exactly, preferring
+/// the canonical palette on a tie.
+pub fn detect_palette(rgba: &[u8]) -> SourcePalette {
+    let mut palettes = SourcePalette::builtin();
+    let scores: Vec<usize> = palettes
+        .iter()
+        .map(|p| {
+            rgba.chunks_exact(4)
+                .filter(|px| p.colors.contains(&RGBColor { r: px[0], g: px[1], b: px[2] }))
+                .count()
+        })
+        .collect();
+
+    let best = (0..palettes.len()).rev().max_by_key(|&i| scores[i]).unwrap_or(0);
+    palettes.swap_remove(best)
+}
+
+/// Convert RGBA image data to CGA color indices using the nearest color of `palette`. Pixels
+/// further than `tolerance` from every palette color are still mapped, and counted in the report.
+pub fn match_image(rgba: &[u8], img_w: u32, palette: &SourcePalette, tolerance: f32) -> (Vec<u8>, MatchReport) {
+    let mut report = MatchReport {
+        palette: palette.name.clone(),
+        pixels: rgba.len() / 4,
+        unmatched: 0,
+        max_distance: 0.0,
+        examples: Vec::new(),
+    };
+    let tolerance_sq = (tolerance * tolerance) as i32;
+
+    let cga_buf = rgba
+        .chunks_exact(4)
+        .enumerate()
+        .map(|(i, px)| {
+            let rgb = RGBColor {
+                r: px[0],
+                g: px[1],
+                b: px[2],
+            };
+            let (index, d) = palette.nearest(rgb);
+
+            report.max_distance = report.max_distance.max((d as f32).sqrt());
+            if d > tolerance_sq {
+                report.unmatched += 1;
+                if report.examples.len() < MAX_EXAMPLES && !report.examples.iter().any(|(_, _, c)| *c == rgb) {
+                    report.examples.push((i as u32 % img_w, i as u32 / img_w, rgb));
+                }
+            }
+            index
+        })
+        .collect();
+
+    (cga_buf, report)
+}
@@ -0,0 +1,305 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    Output scaling
+
+    The decoders produce one pixel per hdot and one line per scanline, which
+    looks squashed vertically. Images can be line doubled, or fitted into a
+    target size at the 4:3 aspect ratio of the monitor. Scaling is separable
+    and an axis whose size doesn't change is left alone, so the horizontal
+    resolution of the composite decode is only resampled if the target
+    width asks for it. Values are not clamped, so super-white survives into
+    float output formats.
+*/
+
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+use crate::output::{FloatImage, FrameSize};
+
+const DISPLAY_ASPECT: f32 = 4.0 / 3.0;
+const LANCZOS_LOBES: f32 = 3.0;
+
+/// How decoded images are scaled before they are written.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScaleMode {
+    None,
+    /// Repeat every line.
+    Double,
+    /// Fit a 4:3 picture into this size, centered over black.
+    Fit(FrameSize),
+}
+
+impl FromStr for ScaleMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(ScaleMode::None),
+            "double" => Ok(ScaleMode::Double),
+            size => size
+                .parse()
+                .map(ScaleMode::Fit)
+                .map_err(|_| "Bad value for scale, expected none, double or a size such as 640x480".to_string()),
+        }
+    }
+}
+
+/// Resampling filter for scaled output.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScaleFilter {
+    Nearest,
+    Bilinear,
+    Lanczos,
+}
+
+impl FromStr for ScaleFilter {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "nearest" => Ok(ScaleFilter::Nearest),
+            "bilinear" => Ok(ScaleFilter::Bilinear),
+            "lanczos" => Ok(ScaleFilter::Lanczos),
+            _ => Err("Bad value for scale filter, expected nearest, bilinear or lanczos".to_string()),
+        }
+    }
+}
+
+impl ScaleFilter {
+    /// Half width of the kernel, in source pixels when magnifying.
+    fn support(&self) -> f32 {
+        match self {
+            ScaleFilter::Nearest => 0.5,
+            ScaleFilter::Bilinear => 1.0,
+            ScaleFilter::Lanczos => LANCZOS_LOBES,
+        }
+    }
+
+    fn kernel(&self, x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            ScaleFilter::Nearest => {
+                if x < 0.5 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            ScaleFilter::Bilinear => (1.0 - x).max(0.0),
+            ScaleFilter::Lanczos => {
+                if x >= LANCZOS_LOBES {
+                    0.0
+                } else {
+                    sinc(x) * sinc(x / LANCZOS_LOBES)
+                }
+            }
+        }
+    }
+}
+
+#[inline]
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Where a scaled picture sits in the output image, in output pixels.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Placement {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Scales decoded images for output.
+#[derive(Copy, Clone, Debug)]
+pub struct Scaler {
+    pub mode: ScaleMode,
+    pub filter: ScaleFilter,
+}
+
+impl Scaler {
+    pub fn new(mode: ScaleMode, filter: ScaleFilter) -> Self {
+        Self { mode, filter }
+    }
+
+    /// Return the size of the output for an image of the given size, and where the picture
+    /// sits within it.
+    pub fn placement(&self, width: u32, height: u32) -> (FrameSize, Placement) {
+        let whole = |width, height| (FrameSize { width, height }, Placement { x: 0, y: 0, width, height });
+        match self.mode {
+            ScaleMode::None => whole(width, height),
+            ScaleMode::Double => whole(width, height * 2),
+            ScaleMode::Fit(size) => {
+                // The largest 4:3 rectangle that fits.
+                let (fit_w, fit_h) = if size.width as f32 >= size.height as f32 * DISPLAY_ASPECT {
+                    (((size.height as f32 * DISPLAY_ASPECT).round() as u32).min(size.width), size.height)
+                } else {
+                    (size.width, ((size.width as f32 / DISPLAY_ASPECT).round() as u32).min(size.height))
+                };
+                let picture = Placement {
+                    x: (size.width - fit_w) / 2,
+                    y: (size.height - fit_h) / 2,
+                    width: fit_w,
+                    height: fit_h,
+                };
+                (size, picture)
+            }
+        }
+    }
+
+    /// Return the scaled image.
+    pub fn apply(&self, img: FloatImage) -> FloatImage {
+        match self.mode {
+            ScaleMode::None => img,
+            ScaleMode::Double => {
+                let w = img.width as usize;
+                let pixels = img.pixels.chunks(w).flat_map(|line| line.iter().chain(line)).copied().collect();
+                FloatImage {
+                    width: img.width,
+                    height: img.height * 2,
+                    pixels,
+                }
+            }
+            ScaleMode::Fit(size) => {
+                let (_, picture) = self.placement(img.width, img.height);
+                let scaled = self.resize(&img, picture.width, picture.height);
+                if (picture.width, picture.height) == (size.width, size.height) {
+                    return scaled;
+                }
+
+                let mut out = FloatImage::new(size.width, size.height);
+                for (y, line) in scaled.pixels.chunks(picture.width as usize).enumerate() {
+                    let o = (picture.y as usize + y) * size.width as usize + picture.x as usize;
+                    out.pixels[o..o + picture.width as usize].copy_from_slice(line);
+                }
+                out
+            }
+        }
+    }
+
+    /// Resample to a new size, one axis at a time, skipping an axis that doesn't change.
+    pub fn resize(&self, img: &FloatImage, width: u32, height: u32) -> FloatImage {
+        let mut out = img.clone();
+        if width != out.width {
+            out = self.resample(&out, width, true);
+        }
+        if height != out.height {
+            out = self.resample(&out, height, false);
+        }
+        out
+    }
+
+    fn resample(&self, img: &FloatImage, new_len: u32, horizontal: bool) -> FloatImage {
+        let (src_len, other) = if horizontal {
+            (img.width, img.height)
+        } else {
+            (img.height, img.width)
+        };
+        let taps = self.taps(src_len as usize, new_len as usize);
+
+        let (w, h) = if horizontal { (new_len, img.height) } else { (img.width, new_len) };
+        let mut out = FloatImage::new(w, h);
+
+        for o in 0..other as usize {
+            for (i, (start, weights)) in taps.iter().enumerate() {
+                let mut sum = [0.0f32; 3];
+                for (k, weight) in weights.iter().enumerate() {
+                    let p = if horizontal {
+                        img.pixels[o * img.width as usize + start + k]
+                    } else {
+                        img.pixels[(start + k) * img.width as usize + o]
+                    };
+                    for c in 0..3 {
+                        sum[c] += p[c] * weight;
+                    }
+                }
+                let index = if horizontal { o * w as usize + i } else { i * w as usize + o };
+                out.pixels[index] = sum;
+            }
+        }
+        out
+    }
+
+    /// Return the first source pixel and the normalized weights contributing to each output
+    /// pixel. The kernel is widened when minifying, so it also acts as the low pass filter.
+    fn taps(&self, src_len: usize, dst_len: usize) -> Vec<(usize, Vec<f32>)> {
+        let ratio = src_len as f32 / dst_len as f32;
+        let stretch = ratio.max(1.0);
+        let support = self.filter.support() * stretch;
+
+        (0..dst_len)
+            .map(|i| {
+                let center = (i as f32 + 0.5) * ratio;
+                if self.filter == ScaleFilter::Nearest {
+                    return ((center as usize).min(src_len - 1), vec![1.0]);
+                }
+
+                let start = (center - support).floor().max(0.0) as usize;
+                let end = ((center + support).ceil() as usize).min(src_len);
+                let mut weights: Vec<f32> = (start..end)
+                    .map(|s| self.filter.kernel((s as f32 + 0.5 - center) / stretch))
+                    .collect();
+                let total: f32 = weights.iter().sum();
+                if total != 0.0 {
+                    weights.iter_mut().for_each(|w| *w /= total);
+                }
+                (start, weights)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn taps_sum_to_one() {
+        for filter in [ScaleFilter::Nearest, ScaleFilter::Bilinear, ScaleFilter::Lanczos] {
+            let scaler = Scaler::new(ScaleMode::Double, filter);
+            for (src_len, dst_len) in [(320, 640), (640, 200), (200, 480), (200, 200)] {
+                for (start, weights) in scaler.taps(src_len, dst_len) {
+                    assert!(start + weights.len() <= src_len);
+                    let total: f32 = weights.iter().sum();
+                    assert!((total - 1.0).abs() < 1e-4, "{:?} {}->{}: {}", filter, src_len, dst_len, total);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fit_fills_4_3_frame() {
+        let size = FrameSize { width: 640, height: 480 };
+        let scaler = Scaler::new(ScaleMode::Fit(size), ScaleFilter::Bilinear);
+        let (frame, picture) = scaler.placement(640, 200);
+        assert_eq!(frame, size);
+        assert_eq!(picture, Placement { x: 0, y: 0, width: 640, height: 480 });
+    }
+}
@@ -0,0 +1,351 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    Graphics mode VRAM decoding
+
+    Generates the RGBI stream of the CGA graphics modes from a dump of video
+    memory and the values of the mode and color select registers, one CGA
+    color index per hdot.
+
+    The Plantronics ColorPlus adds a second 16K plane at offset 0x4000 and a
+    control register at 3DDh. Its 320x200x16 mode takes the low two bits of
+    each pixel from plane 0 and the high two bits from plane 1; its 640x200x4
+    mode takes one bit from each plane and maps the result through the usual
+    320x200 palette.
+
+    Dumps may also be BSAVE images, as written by BASIC's BSAVE statement
+    with the segment set to video memory.
+*/
+
+use std::path::Path;
+use std::str::FromStr;
+
+pub const VRAM_LINES: u32 = 200;
+pub const VRAM_HDOTS: u32 = 640;
+
+const ROW_BYTES: usize = 80;
+const ODD_BANK: usize = 0x2000; // Odd scanlines are stored in the second 8K bank
+const PLANE_SIZE: usize = 0x4000;
+
+const BSAVE_MAGIC: u8 = 0xFD;
+const BSAVE_HEADER_SIZE: usize = 7;
+const VRAM_ADDRESS: usize = 0xB8000;
+
+// Mode register (3D8h) bits
+pub const MODE_TEXT_80: u8 = 0x01;
+pub const MODE_GRAPHICS: u8 = 0x02;
+pub const MODE_BW: u8 = 0x04;
+pub const MODE_ENABLE: u8 = 0x08;
+pub const MODE_HIRES_GRAPHICS: u8 = 0x10;
+
+// Color select register (3D9h) bits
+pub const COLOR_INTENSE: u8 = 0x10;
+pub const COLOR_PALETTE: u8 = 0x20;
+
+// ColorPlus control register (3DDh) bits
+pub const COLORPLUS_320X200: u8 = 0x10;
+pub const COLORPLUS_640X200: u8 = 0x20;
+pub const COLORPLUS_PLANE_SWAP: u8 = 0x40;
+
+/// A register value, given in decimal or as hex with a 0x prefix.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Register(pub u8);
+
+impl FromStr for Register {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let value = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u8::from_str_radix(hex, 16),
+            None => s.parse::<u8>(),
+        };
+        value
+            .map(Register)
+            .map_err(|e| format!("Bad register value '{}': {}", s, e))
+    }
+}
+
+/// Graphics modes, as shorthand for the register values that select them.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VramMode {
+    Cga320,
+    Cga640,
+    ColorPlus320,
+    ColorPlus640,
+}
+
+impl FromStr for VramMode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "cga320" | "320x200x4" => Ok(VramMode::Cga320),
+            "cga640" | "640x200x2" => Ok(VramMode::Cga640),
+            "colorplus320" | "320x200x16" => Ok(VramMode::ColorPlus320),
+            "colorplus640" | "640x200x4" => Ok(VramMode::ColorPlus640),
+            _ => Err("Bad value for VRAM mode".to_string()),
+        }
+    }
+}
+
+/// The registers that control graphics mode display.
+#[derive(Copy, Clone, Debug)]
+pub struct VideoRegisters {
+    pub mode: u8,
+    pub color_select: u8,
+    pub colorplus: u8,
+}
+
+impl VramMode {
+    /// Return the register values the BIOS would use for this mode.
+    pub fn registers(&self) -> VideoRegisters {
+        let (mode, color_select, colorplus) = match self {
+            VramMode::Cga320 => (MODE_GRAPHICS | MODE_ENABLE, COLOR_PALETTE | COLOR_INTENSE, 0),
+            VramMode::Cga640 => (MODE_GRAPHICS | MODE_ENABLE | MODE_HIRES_GRAPHICS, 0x0F, 0),
+            VramMode::ColorPlus320 => (MODE_GRAPHICS | MODE_ENABLE, 0, COLORPLUS_320X200),
+            VramMode::ColorPlus640 => (
+                MODE_GRAPHICS | MODE_ENABLE | MODE_HIRES_GRAPHICS,
+                COLOR_PALETTE | COLOR_INTENSE,
+                COLORPLUS_640X200,
+            ),
+        };
+        VideoRegisters {
+            mode,
+            color_select,
+            colorplus,
+        }
+    }
+}
+
+impl VideoRegisters {
+    /// Return the four colors of the 320x200 palette, including the background color.
+    fn palette(&self) -> [u8; 4] {
+        let intense = if self.color_select & COLOR_INTENSE != 0 { 8 } else { 0 };
+        let colors = if self.mode & MODE_BW != 0 {
+            [3, 4, 7] // Cyan, red, white
+        } else if self.color_select & COLOR_PALETTE != 0 {
+            [3, 5, 7] // Cyan, magenta, white
+        } else {
+            [2, 4, 6] // Green, red, brown
+        };
+        [
+            self.color_select & 0x0F,
+            colors[0] | intense,
+            colors[1] | intense,
+            colors[2] | intense,
+        ]
+    }
+
+    /// Return the width of a pixel in hdots and the colors a pixel can take, by pixel value.
+    pub fn pixel_colors(&self) -> (u32, Vec<u8>) {
+        let hires = self.mode & MODE_HIRES_GRAPHICS != 0;
+        let colorplus_mode = self.colorplus & (COLORPLUS_320X200 | COLORPLUS_640X200);
+
+        match (hires, colorplus_mode) {
+            (false, COLORPLUS_320X200) => (2, (0..16).collect()),
+            (true, COLORPLUS_640X200) => (1, self.palette().to_vec()),
+            (false, _) => (2, self.palette().to_vec()),
+            (true, _) => (1, vec![0, self.color_select & 0x0F]),
+        }
+    }
+}
+
+/// Return the byte offset of a graphics mode scanline within a plane.
+#[inline]
+fn row_offset(y: u32) -> usize {
+    (y as usize & 1) * ODD_BANK + (y as usize >> 1) * ROW_BYTES
+}
+
+/// Read a VRAM dump from a file, either raw or saved with BASIC's BSAVE.
+pub fn load_vram(path: &Path) -> Result<Vec<u8>, String> {
+    let data = std::fs::read(path).map_err(|e| format!("Couldn't open VRAM dump '{:?}': {}", path, e))?;
+    Ok(parse_bsave(&data).unwrap_or(data))
+}
+
+/// Return whether a file is a BSAVE image.
+pub fn is_bsave(path: &Path) -> bool {
+    std::fs::read(path).map(|data| parse_bsave(&data).is_some()).unwrap_or(false)
+}
+
+/// Return the video memory held by a BSAVE image, or None if `data` isn't one. The 7 byte
+/// header gives the segment, offset and length of the saved memory; it is placed at its
+/// offset from B800:0000, and padded to at least one plane for images saved short.
+fn parse_bsave(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < BSAVE_HEADER_SIZE || data[0] != BSAVE_MAGIC {
+        return None;
+    }
+    let segment = u16::from_le_bytes([data[1], data[2]]) as usize;
+    let offset = u16::from_le_bytes([data[3], data[4]]) as usize;
+    let length = u16::from_le_bytes([data[5], data[6]]) as usize;
+
+    // Allow the DOS end of file marker after the data.
+    let body = &data[BSAVE_HEADER_SIZE..];
+    if body.len() != length && !(body.len() == length + 1 && body[length] == 0x1A) {
+        return None;
+    }
+
+    let start = (segment * 16 + offset).saturating_sub(VRAM_ADDRESS);
+    let mut vram = vec![0; (start + length).max(PLANE_SIZE)];
+    vram[start..start + length].copy_from_slice(&body[..length]);
+    Some(vram)
+}
+
+/// Generate the RGBI stream for a graphics mode VRAM dump, as a 640x200 image of CGA color
+/// indices, one per hdot.
+pub fn decode_vram(vram: &[u8], regs: &VideoRegisters) -> Result<(Vec<u8>, u32, u32), String> {
+    if regs.mode & MODE_GRAPHICS == 0 {
+        return Err("Text modes are not supported; set the graphics bit of the mode register".to_string());
+    }
+
+    let colorplus_mode = regs.colorplus & (COLORPLUS_320X200 | COLORPLUS_640X200);
+    let planes_needed = if colorplus_mode != 0 { 2 } else { 1 };
+    let plane_used = ODD_BANK + row_offset(VRAM_LINES - 2) + ROW_BYTES;
+    let needed = (planes_needed - 1) * PLANE_SIZE + plane_used;
+    if vram.len() < needed {
+        return Err(format!(
+            "VRAM dump is {} bytes, this mode needs at least {}",
+            vram.len(),
+            needed
+        ));
+    }
+
+    let (plane0, plane1) = if planes_needed == 2 {
+        let (a, b) = vram.split_at(PLANE_SIZE);
+        if regs.colorplus & COLORPLUS_PLANE_SWAP != 0 {
+            (b, a)
+        } else {
+            (a, b)
+        }
+    } else {
+        (vram, vram)
+    };
+
+    let mut cga_buf = vec![0; (VRAM_HDOTS * VRAM_LINES) as usize];
+    if regs.mode & MODE_ENABLE == 0 {
+        // Video disabled; the card outputs black.
+        return Ok((cga_buf, VRAM_HDOTS, VRAM_LINES));
+    }
+
+    let hires = regs.mode & MODE_HIRES_GRAPHICS != 0;
+    let palette = regs.palette();
+
+    for (y, line) in cga_buf.chunks_mut(VRAM_HDOTS as usize).enumerate() {
+        let row = row_offset(y as u32);
+        let bytes0 = &plane0[row..row + ROW_BYTES];
+        let bytes1 = &plane1[row..row + ROW_BYTES];
+
+        for (x, hdot) in line.iter_mut().enumerate() {
+            let byte = x / 8;
+            let (b0, b1) = (bytes0[byte], bytes1[byte]);
+
+            *hdot = match (hires, colorplus_mode) {
+                (false, COLORPLUS_320X200) => {
+                    // 4 pixels of 2 hdots per byte; plane 1 holds the high bits.
+                    let shift = 6 - ((x / 2) % 4) * 2;
+                    ((b0 >> shift) & 3) | (((b1 >> shift) & 3) << 2)
+                }
+                (true, COLORPLUS_640X200) => {
+                    let shift = 7 - (x % 8);
+                    palette[(((b0 >> shift) & 1) | (((b1 >> shift) & 1) << 1)) as usize]
+                }
+                (false, _) => {
+                    let shift = 6 - ((x / 2) % 4) * 2;
+                    palette[((b0 >> shift) & 3) as usize]
+                }
+                (true, _) => {
+                    // Foreground is the color select color, background is black.
+                    let shift = 7 - (x % 8);
+                    if (b0 >> shift) & 1 != 0 {
+                        regs.color_select & 0x0F
+                    } else {
+                        0
+                    }
+                }
+            };
+        }
+    }
+
+    Ok((cga_buf, VRAM_HDOTS, VRAM_LINES))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a BSAVE image of `body` saved from the given segment and offset.
+    fn bsave(segment: u16, offset: u16, body: &[u8]) -> Vec<u8> {
+        let mut data = vec![BSAVE_MAGIC];
+        data.extend_from_slice(&segment.to_le_bytes());
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn colorplus_320_combines_planes() {
+        let mut vram = vec![0; 2 * PLANE_SIZE];
+        vram[0] = 0b11_10_01_00;
+        vram[PLANE_SIZE] = 0b00_01_10_11;
+        // The second scanline is in the odd bank.
+        vram[ODD_BANK] = 0xFF;
+
+        let mut regs = VramMode::ColorPlus320.registers();
+        let (cga_buf, _, _) = decode_vram(&vram, &regs).unwrap();
+        assert_eq!(cga_buf[..8], [3, 3, 6, 6, 9, 9, 12, 12]);
+        assert_eq!(cga_buf[VRAM_HDOTS as usize], 3);
+
+        regs.colorplus |= COLORPLUS_PLANE_SWAP;
+        let (cga_buf, _, _) = decode_vram(&vram, &regs).unwrap();
+        assert_eq!(cga_buf[..8], [12, 12, 9, 9, 6, 6, 3, 3]);
+        assert_eq!(cga_buf[VRAM_HDOTS as usize], 12);
+    }
+
+    #[test]
+    fn bsave_allows_end_of_file_marker() {
+        let mut data = bsave(0xB800, 0, &[1, 2, 3, 4]);
+        data.push(0x1A);
+
+        let vram = parse_bsave(&data).unwrap();
+        assert_eq!(vram.len(), PLANE_SIZE);
+        assert_eq!(vram[..4], [1, 2, 3, 4]);
+        assert!(vram[4..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn bsave_rejects_length_mismatch() {
+        let mut data = bsave(0xB800, 0, &[1, 2, 3, 4]);
+        data.push(0x00);
+        assert!(parse_bsave(&data).is_none());
+        assert!(parse_bsave(&data[..data.len() - 2]).is_none());
+    }
+
+    #[test]
+    fn bsave_places_data_at_its_offset() {
+        let vram = parse_bsave(&bsave(0xB800, ODD_BANK as u16, &[0xAA; 2])).unwrap();
+        assert_eq!(vram[ODD_BANK..ODD_BANK + 2], [0xAA, 0xAA]);
+        assert_eq!(vram[0], 0);
+    }
+}
@@ -0,0 +1,197 @@
+/*
+    cga_artifact_color
+    https://github.com/dbalsom/cga_artifact_color/
+
+    Copyright 2022-2023 Daniel Balsom
+
+    Permission is hereby granted, free of charge, to any person obtaining a
+    copy of this software and associated documentation files (the “Software”),
+    to deal in the Software without restriction, including without limitation
+    the rights to use, copy, modify, merge, publish, distribute, sublicense,
+    and/or sell copies of the Software, and to permit persons to whom the
+    Software is furnished to do so, subject to the following conditions:
+
+    The above copyright notice and this permission notice shall be included in
+    all copies or substantial portions of the Software.
+
+    THE SOFTWARE IS PROVIDED “AS IS”, WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+    IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+    FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+    AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+    LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING
+    FROM, OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER
+    DEALINGS IN THE SOFTWARE.
+
+    --------------------------------------------------------------------------
+
+    Full composite waveform generation
+
+    Wraps the active video produced by the composite encoder in complete CGA
+    scanlines: border, horizontal sync, color burst and a vertical interval,
+    so a decoder can lock to sync and burst the way a monitor does.
+
+    Timings are in hdots of the 14.318 MHz pixel clock. Each line starts with
+    active video so that sample 0 of a line keeps the same color clock phase
+    as sample 0 of the active signal.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::composite::{resample_halves, CompositeSignal, EncoderModel, ModelParams, COLOR_GEN_HALF, LINE_HDOTS};
+
+pub const FRAME_LINES: u32 = 262; // Scanlines per non-interlaced field
+pub const ACTIVE_LINES: u32 = 200;
+pub const ACTIVE_HDOTS: u32 = 640;
+
+pub const HSYNC_START: u32 = 720; // Right border ends here
+pub const HSYNC_HDOTS: u32 = 64; // Nominal 4.5us sync pulse
+pub const BURST_START: u32 = HSYNC_START + HSYNC_HDOTS + 8;
+pub const BURST_HDOTS: u32 = 36; // 9 cycles of subcarrier
+pub const BLANK_END: u32 = HSYNC_START + 160; // Left border starts here
+
+pub const VSYNC_START: u32 = 224; // First line of vertical sync
+pub const VSYNC_LINES: u32 = 3;
+pub const VBLANK_LINES: u32 = 16; // Lines blanked for vertical retrace, starting at VSYNC_START
+
+// Signal levels, in the units of the active signal where 0.0 is blanking/black and 1.0 is
+// bright white.
+pub const SYNC_LEVEL: f32 = -0.4;
+pub const BLANK_LEVEL: f32 = 0.0;
+pub const BURST_AMPLITUDE: f32 = 0.2;
+
+const BURST_COLOR: usize = 6; // The CGA generates its burst from the color 6 chroma signal
+
+/// Sample positions of the regions of a full frame, for consumers of exported signals.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct FrameTiming {
+    pub active_start: u32,
+    pub active_samples: u32,
+    pub active_lines: u32,
+    pub hsync_start: u32,
+    pub hsync_samples: u32,
+    pub burst_start: u32,
+    pub burst_samples: u32,
+    pub vsync_line: u32,
+    pub vsync_lines: u32,
+}
+
+impl FrameTiming {
+    pub fn new(samples_per_hdot: u32) -> Self {
+        Self {
+            active_start: 0,
+            active_samples: ACTIVE_HDOTS * samples_per_hdot,
+            active_lines: ACTIVE_LINES,
+            hsync_start: HSYNC_START * samples_per_hdot,
+            hsync_samples: HSYNC_HDOTS * samples_per_hdot,
+            burst_start: BURST_START * samples_per_hdot,
+            burst_samples: BURST_HDOTS * samples_per_hdot,
+            vsync_line: VSYNC_START,
+            vsync_lines: VSYNC_LINES,
+        }
+    }
+}
+
+/// Return the level of the color burst at the given sample of a line.
+#[inline]
+fn burst_level(s: u32, samples_per_hdot: u32) -> f32 {
+    let hdot = ((s / samples_per_hdot) % 4) as usize;
+    let halves = [
+        COLOR_GEN_HALF[BURST_COLOR][hdot * 2],
+        COLOR_GEN_HALF[BURST_COLOR][hdot * 2 + 1],
+    ];
+    (resample_halves(halves, s % samples_per_hdot, samples_per_hdot) - 0.5) * 2.0 * BURST_AMPLITUDE
+}
+
+/// Build a complete 262 line frame around an active composite signal. Active images taller
+/// than 200 lines are assumed to be line doubled and are sampled down to 200 lines. `border`
+/// is the CGA color index of the overscan area.
+pub fn encode_frame(
+    active: &CompositeSignal,
+    border: u8,
+    model: &EncoderModel,
+    params: &ModelParams,
+) -> CompositeSignal {
+    let spd = active.samples_per_hdot;
+    let line_len = LINE_HDOTS * spd;
+    let mut frame = CompositeSignal::new(line_len, FRAME_LINES, spd);
+
+    // Encode a whole line of border color so border samples keep their color clock phase.
+    let border_buf = vec![border; LINE_HDOTS as usize];
+    let border_line = CompositeSignal::encode(&border_buf, LINE_HDOTS, 1, spd, model, params);
+
+    let active_lines = active.height.min(ACTIVE_LINES);
+    let active_w = active.width.min(ACTIVE_HDOTS * spd);
+
+    for (y, line) in frame.samples.chunks_mut(line_len as usize).enumerate() {
+        let y = y as u32;
+        let in_vsync = (VSYNC_START..VSYNC_START + VSYNC_LINES).contains(&y);
+        let in_vblank = (VSYNC_START..VSYNC_START + VBLANK_LINES).contains(&y);
+
+        for (s, sample) in line.iter_mut().enumerate() {
+            let s = s as u32;
+            let hdot = s / spd;
+            let in_hsync = (HSYNC_START..HSYNC_START + HSYNC_HDOTS).contains(&hdot);
+
+            *sample = if in_vsync {
+                // Broad pulses: the sync level inverts, returning to blank only where hsync would be.
+                if in_hsync {
+                    BLANK_LEVEL
+                } else {
+                    SYNC_LEVEL
+                }
+            } else if in_hsync {
+                SYNC_LEVEL
+            } else if (BURST_START..BURST_START + BURST_HDOTS).contains(&hdot) {
+                burst_level(s, spd)
+            } else if (HSYNC_START..BLANK_END).contains(&hdot) || in_vblank {
+                BLANK_LEVEL
+            } else if y < active_lines && s < active_w {
+                let src_y = if active.height > ACTIVE_LINES {
+                    y * active.height / ACTIVE_LINES
+                } else {
+                    y
+                };
+                active.samples[(src_y * active.width + s) as usize]
+            } else {
+                border_line.samples[s as usize]
+            };
+        }
+    }
+
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_has_sync_burst_and_broad_pulses() {
+        let spd = 2;
+        let black = vec![0; (ACTIVE_HDOTS * ACTIVE_LINES) as usize];
+        let params = ModelParams::default();
+        let active = CompositeSignal::encode(&black, ACTIVE_HDOTS, ACTIVE_LINES, spd, &EncoderModel::Digital, &params);
+        let frame = encode_frame(&active, 0, &EncoderModel::Digital, &params);
+        let line = |y: u32| &frame.samples[(y * frame.width) as usize..((y + 1) * frame.width) as usize];
+        let hsync = (HSYNC_START * spd) as usize..((HSYNC_START + HSYNC_HDOTS) * spd) as usize;
+        let burst = (BURST_START * spd) as usize..((BURST_START + BURST_HDOTS) * spd) as usize;
+
+        // Horizontal sync, then blanking up to the burst.
+        assert!(line(0)[hsync.clone()].iter().all(|&s| s == SYNC_LEVEL));
+        assert_eq!(line(0)[burst.start - 1], BLANK_LEVEL);
+
+        // The burst swings around blanking at its amplitude.
+        let peak = line(0)[burst.clone()].iter().fold(0.0f32, |m, s| m.max(s.abs()));
+        assert!((peak - BURST_AMPLITUDE).abs() < 1.0e-6);
+        let mean: f32 = line(0)[burst.clone()].iter().sum::<f32>() / burst.len() as f32;
+        assert!(mean.abs() < 1.0e-6);
+
+        // Broad pulses invert the sync level on the vertical sync lines.
+        for y in VSYNC_START..VSYNC_START + VSYNC_LINES {
+            assert!(line(y)[hsync.clone()].iter().all(|&s| s == BLANK_LEVEL));
+            assert!(line(y)[..hsync.start].iter().all(|&s| s == SYNC_LEVEL));
+            assert!(line(y)[burst.clone()].iter().all(|&s| s == SYNC_LEVEL));
+        }
+        assert!(line(VSYNC_START - 1)[..hsync.start].iter().all(|&s| s != SYNC_LEVEL));
+    }
+}